@@ -1,19 +1,24 @@
-use super::{CmioBuffer, CmioError, CmioSetup, CmioYield, Result};
+use super::{
+    check_buffer_sizes, check_response_len, CmioBuffer, CmioError, CmioSetup, CmioYield, Result,
+    DEFAULT_DEVICE_PATH, HTIF_DEVICE_YIELD, HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT,
+    HTIF_YIELD_CMD_AUTOMATIC, HTIF_YIELD_CMD_MANUAL,
+};
 use libc::{
-    c_char, c_int, c_void, close, mmap, munmap, open, O_RDWR, PROT_READ, PROT_WRITE, MAP_FAILED,
-    MAP_SHARED,
+    c_int, c_void, close, mmap, munmap, open, O_RDWR, PROT_READ, PROT_WRITE, MAP_FAILED, MAP_SHARED,
 };
 use nix::{ioctl_read, ioctl_readwrite};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::ptr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 ioctl_read!(cmio_setup, 0xd3, 0, CmioSetup);
 ioctl_readwrite!(cmio_yield, 0xd3, 1, u64);
 
-// HTIF Device constants
-const HTIF_DEVICE_YIELD: u8 = 2;
-// HTIF Commands
-const HTIF_YIELD_CMD_MANUAL: u8 = 1;
-
 /// IO driver for CMIO operations
 pub struct CmioIoDriver {
     fd: c_int,
@@ -21,12 +26,25 @@ pub struct CmioIoDriver {
     tx_len: usize,
     rx_ptr: *mut u8,
     rx_len: usize,
+    last_response_len: Cell<usize>,
 }
 
+// SAFETY: the mapped TX/RX buffers and the fd are owned exclusively by the
+// driver and are only accessed through `&self`/`&mut self`, so moving the
+// driver to another thread is sound. It is not `Sync`; share it behind a Mutex.
+unsafe impl Send for CmioIoDriver {}
+
 impl CmioIoDriver {
     /// Initialize the CMIO driver
     pub fn new() -> Result<Self> {
-        let fd = unsafe { open(b"/dev/cmio\0".as_ptr() as *const c_char, O_RDWR) };
+        Self::with_device_path(Path::new(DEFAULT_DEVICE_PATH))
+    }
+
+    /// Initialize the CMIO driver from the device node at `path`
+    pub fn with_device_path(path: &Path) -> Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| CmioError::InvalidArgument)?;
+        let fd = unsafe { open(path.as_ptr(), O_RDWR) };
 
         if fd < 0 {
             return Err(CmioError::IoError(std::io::Error::last_os_error()));
@@ -44,6 +62,11 @@ impl CmioIoDriver {
             return Err(CmioError::IoError(err));
         }
 
+        if let Err(err) = check_buffer_sizes(setup.tx.length as usize, setup.rx.length as usize) {
+            unsafe { close(fd) };
+            return Err(err);
+        }
+
         let tx_ptr = unsafe {
             mmap(
                 setup.tx.data as *mut c_void,
@@ -85,9 +108,31 @@ impl CmioIoDriver {
             tx_len: setup.tx.length as usize,
             rx_ptr: rx_ptr as *mut u8,
             rx_len: setup.rx.length as usize,
+            last_response_len: Cell::new(0),
         })
     }
 
+    /// Initialize the CMIO driver, giving up if the device is not ready within
+    /// `timeout`
+    ///
+    /// The setup ioctl can block when the device node exists but the emulator
+    /// has not wired up its buffers yet. Initialization runs on a helper
+    /// thread; on timeout that thread is left blocked in the ioctl, and a
+    /// driver it eventually creates is dropped.
+    pub fn new_with_timeout(timeout: Duration) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(Self::new());
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(CmioError::Timeout(timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(CmioError::IoError(std::io::Error::other(
+                "CMIO initialization thread panicked",
+            ))),
+        }
+    }
+
     /// Yield control to the emulator
     pub fn yield_control(&self, yield_data: &mut CmioYield) -> Result<()> {
         if yield_data as *const _ == ptr::null() {
@@ -99,10 +144,14 @@ impl CmioIoDriver {
 
         // Use nix ioctl macro
         if unsafe { cmio_yield(self.fd, &mut response) }.is_err() {
-            return Err(CmioError::IoError(std::io::Error::last_os_error()));
+            return Err(CmioError::YieldFailed {
+                reason: yield_data.reason,
+                source: std::io::Error::last_os_error(),
+            });
         }
 
         *yield_data = Self::unpack(response);
+        self.last_response_len.set(yield_data.data as usize);
         Ok(())
     }
 
@@ -139,6 +188,22 @@ impl CmioIoDriver {
         unsafe { std::slice::from_raw_parts(self.rx_ptr, self.rx_len) }
     }
 
+    /// Get the first `len` bytes of the RX buffer
+    ///
+    /// Fails with `InvalidArgument` if `len` exceeds the mapped RX buffer.
+    pub fn rx_response(&self, len: usize) -> Result<&[u8]> {
+        if len > self.rx_len {
+            return Err(CmioError::InvalidArgument);
+        }
+        Ok(&self.rx_slice()[..len])
+    }
+
+    /// Get the response length reported by the emulator on the most recent
+    /// yield, i.e. how much of the RX buffer is valid
+    pub fn last_response_len(&self) -> usize {
+        self.last_response_len.get()
+    }
+
     /// Get the length of the TX buffer
     pub fn tx_len(&self) -> usize {
         self.tx_len
@@ -150,6 +215,15 @@ impl CmioIoDriver {
     }
 
     /// Send data via CMIO and receive a response
+    ///
+    /// `domain` is passed to the emulator as the yield reason and selects the
+    /// logical channel the data belongs to (see [`crate::DOMAIN_VSOCK`]);
+    /// both ends of a channel must agree on it.
+    ///
+    /// Only the first `yield_data.data` bytes of the RX buffer, the response
+    /// length the emulator writes back during the yield, are returned; an
+    /// empty vector means no response. A reported length larger than the RX
+    /// buffer fails with [`CmioError::InvalidArgument`].
     pub fn send_cmio(&mut self, tx_data: &[u8], domain: u16) -> Result<Vec<u8>> {
         if tx_data.len() > self.tx_len() {
             return Err(CmioError::InvalidArgument);
@@ -165,13 +239,34 @@ impl CmioIoDriver {
             data: tx_data.len() as u32,
         };
         self.yield_control(&mut yield_data)?;
-        // Copy RX buffer
-        let rx_buf = self.rx_slice();
-        let rx_vec = rx_buf[..self.rx_len()].to_vec();
+        // Copy the response out of the RX buffer
+        let resp_len = check_response_len(self.last_response_len(), self.rx_len())?;
+        let rx_vec = self.rx_response(resp_len)?.to_vec();
         Ok(rx_vec)
     }
 }
 
+impl CmioIoDriver {
+    /// Emit an automatic TX report, e.g. for progress reporting
+    ///
+    /// Unlike [`CmioIoDriver::send_cmio`], an automatic yield does not wait
+    /// for input from the host: the emulator consumes the TX data and resumes
+    /// the guest, so there is no response to return.
+    pub fn report(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.tx_len() {
+            return Err(CmioError::InvalidArgument);
+        }
+        self.tx_slice_mut()[..data.len()].copy_from_slice(data);
+        let mut yield_data = CmioYield {
+            dev: HTIF_DEVICE_YIELD,
+            cmd: HTIF_YIELD_CMD_AUTOMATIC,
+            reason: HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT,
+            data: data.len() as u32,
+        };
+        self.yield_control(&mut yield_data)
+    }
+}
+
 impl Drop for CmioIoDriver {
     fn drop(&mut self) {
         unsafe {
@@ -180,4 +275,28 @@ impl Drop for CmioIoDriver {
             close(self.fd);
         }
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_device_path_is_an_io_error() {
+        match CmioIoDriver::with_device_path(Path::new("/nonexistent/cmio")) {
+            Err(CmioError::IoError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound)
+            }
+            Err(other) => panic!("expected IoError, got {:?}", other),
+            Ok(_) => panic!("opened a nonexistent device"),
+        }
+    }
+
+    #[test]
+    fn device_path_with_nul_is_rejected() {
+        assert!(matches!(
+            CmioIoDriver::with_device_path(Path::new("/dev/cm\0io")),
+            Err(CmioError::InvalidArgument)
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use nix::{ioctl_read, ioctl_readwrite};
 use thiserror::Error;
+use std::fs::OpenOptions;
 use std::path::Path;
 
 #[cfg(not(feature = "mock_cmio"))]
@@ -20,12 +21,46 @@ pub enum CmioError {
     InvalidResponse,
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("CMIO yield failed (reason {reason}): {source}")]
+    YieldFailed { reason: u16, source: std::io::Error },
     #[error("Memory mapping failed")]
     MmapFailed,
+    #[error("Timed out after {0:?} waiting for the CMIO device")]
+    Timeout(std::time::Duration),
+    #[error("CMIO buffers too small: tx {tx} bytes, rx {rx} bytes, need at least {min}")]
+    BufferTooSmall { tx: usize, rx: usize, min: usize },
 }
 
 pub type Result<T> = std::result::Result<T, CmioError>;
 
+/// Smallest TX/RX buffer size that can carry a vsock packet header.
+pub const MIN_BUFFER_SIZE: usize = vsock_protocol::HDR_SIZE;
+
+/// Check that the TX and RX buffers reported by the emulator are usable
+fn check_buffer_sizes(tx: usize, rx: usize) -> Result<()> {
+    if tx < MIN_BUFFER_SIZE || rx < MIN_BUFFER_SIZE {
+        return Err(CmioError::BufferTooSmall {
+            tx,
+            rx,
+            min: MIN_BUFFER_SIZE,
+        });
+    }
+    Ok(())
+}
+
+/// Validate a response length reported by the emulator against the RX buffer
+///
+/// The emulator reports how many bytes of the RX buffer it filled in
+/// `CmioYield::data`; that value is the only authority on how much of the
+/// buffer is valid. A length larger than the buffer cannot be honoured and
+/// is rejected with [`CmioError::InvalidArgument`] instead of being clamped.
+fn check_response_len(reported: usize, rx_len: usize) -> Result<usize> {
+    if reported > rx_len {
+        return Err(CmioError::InvalidArgument);
+    }
+    Ok(reported)
+}
+
 // IOCTL definitions using nix macros for cross-platform compatibility
 ioctl_read!(cmio_setup, 0xd3, 0, CmioSetup);
 ioctl_readwrite!(cmio_yield, 0xd3, 1, u64);
@@ -53,9 +88,35 @@ pub struct CmioYield {
     pub data: u32,
 }
 
+/// CMIO domain carrying vsock packets between the guest agent and the runner
+pub const DOMAIN_VSOCK: u16 = 0x27;
+
+/// Device node the driver opens by default.
+pub const DEFAULT_DEVICE_PATH: &str = "/dev/cmio";
+
 /// Check if /dev/cmio device exists
 pub fn is_cmio_device_present() -> bool {
-    Path::new("/dev/cmio").exists()
+    is_cmio_device_present_at(Path::new(DEFAULT_DEVICE_PATH))
+}
+
+/// Check if a CMIO device node exists at `path`
+pub fn is_cmio_device_present_at(path: &Path) -> bool {
+    path.exists()
+}
+
+/// Check if /dev/cmio can actually be opened for reading and writing
+///
+/// Unlike [`is_cmio_device_present`], this is false when the node exists but
+/// the process lacks permission (`EACCES`) or nothing backs it (`ENODEV`).
+/// The probe's file descriptor is closed before returning.
+pub fn is_cmio_device_usable() -> bool {
+    is_cmio_device_usable_at(Path::new(DEFAULT_DEVICE_PATH))
+}
+
+/// Check if the CMIO device node at `path` can be opened for reading and
+/// writing
+pub fn is_cmio_device_usable_at(path: &Path) -> bool {
+    OpenOptions::new().read(true).write(true).open(path).is_ok()
 }
 
 // HTIF Device constants
@@ -65,3 +126,54 @@ const HTIF_YIELD_CMD_AUTOMATIC: u8 = 0;
 const HTIF_YIELD_CMD_MANUAL: u8 = 1;
 // HTIF Automatic reasons
 const HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT: u16 = 4;
+
+#[cfg(feature = "tokio")]
+impl CmioIoDriver {
+    /// Yield control to the emulator from async code
+    ///
+    /// The yield ioctl still blocks until the emulator resumes the guest; it
+    /// runs on tokio's blocking pool via `spawn_blocking`, occupying one of its
+    /// threads instead of an async worker. The driver's lock is held for the
+    /// whole yield.
+    pub async fn yield_control_async(
+        driver: std::sync::Arc<std::sync::Mutex<Self>>,
+        mut yield_data: CmioYield,
+    ) -> Result<CmioYield> {
+        tokio::task::spawn_blocking(move || {
+            driver.lock().unwrap().yield_control(&mut yield_data)?;
+            Ok(yield_data)
+        })
+        .await
+        .map_err(|e| CmioError::IoError(std::io::Error::other(e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_buffers_are_too_small() {
+        assert!(check_buffer_sizes(MIN_BUFFER_SIZE, MIN_BUFFER_SIZE).is_ok());
+        match check_buffer_sizes(MIN_BUFFER_SIZE - 1, 4096) {
+            Err(CmioError::BufferTooSmall { tx, rx, min }) => {
+                assert_eq!((tx, rx, min), (MIN_BUFFER_SIZE - 1, 4096, MIN_BUFFER_SIZE));
+            }
+            other => panic!("expected BufferTooSmall, got {:?}", other),
+        }
+        assert!(matches!(
+            check_buffer_sizes(4096, 0),
+            Err(CmioError::BufferTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn response_len_must_fit_the_rx_buffer() {
+        assert_eq!(check_response_len(0, 64).unwrap(), 0);
+        assert_eq!(check_response_len(64, 64).unwrap(), 64);
+        assert!(matches!(
+            check_response_len(65, 64),
+            Err(CmioError::InvalidArgument)
+        ));
+    }
+}
@@ -1,5 +1,11 @@
-use super::{CmioError, Result, CmioYield};
+use super::{
+    check_buffer_sizes, check_response_len, CmioError, CmioYield, Result, HTIF_DEVICE_YIELD,
+    HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT, HTIF_YIELD_CMD_AUTOMATIC, HTIF_YIELD_CMD_MANUAL,
+};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
 use vsock_protocol::{
     VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RW,
 };
@@ -10,7 +16,12 @@ pub struct CmioIoDriver {
     tx_buf: Vec<u8>,
     rx_buf: Vec<u8>,
     pending_requests: Vec<Vec<u8>>,
+    data_replies: Vec<Vec<u8>>,
     pending_responses: HashMap<u32, Vec<u8>>,
+    last_response_len: usize,
+    reports: Vec<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+    yields: RefCell<Vec<CmioYield>>,
 }
 
 impl CmioIoDriver {
@@ -18,17 +29,42 @@ impl CmioIoDriver {
     /// This will immediately prepare a vsock connection request in the RX buffer,
     /// simulating an incoming connection from the host.
     pub fn new() -> Result<Self> {
+        Self::with_buffer_sizes(4096, 4096)
+    }
+
+    /// Initialize the mock CMIO driver; there is no device node, so `path` is
+    /// ignored.
+    pub fn with_device_path(_path: &Path) -> Result<Self> {
+        Self::new()
+    }
+
+    /// Initialize the mock CMIO driver; the mock is always ready, so the
+    /// timeout never expires.
+    pub fn new_with_timeout(_timeout: Duration) -> Result<Self> {
+        Self::new()
+    }
+
+    /// Initialize the mock CMIO driver with TX and RX buffers of the given sizes,
+    /// validated the same way as the buffers reported by the emulator.
+    pub fn with_buffer_sizes(tx_len: usize, rx_len: usize) -> Result<Self> {
+        check_buffer_sizes(tx_len, rx_len)?;
         let driver = CmioIoDriver {
-            tx_buf: vec![0; 4096],
-            rx_buf: vec![0; 4096],
+            tx_buf: vec![0; tx_len],
+            rx_buf: vec![0; rx_len],
             pending_requests: Vec::new(),
+            data_replies: Vec::new(),
             pending_responses: HashMap::new(),
+            last_response_len: 0,
+            reports: Vec::new(),
+            sent: Vec::new(),
+            yields: RefCell::new(Vec::new()),
         };
         Ok(driver)
     }
 
-    /// Mock yield control
-    pub fn yield_control(&self, _yield_data: &mut CmioYield) -> Result<()> {
+    /// Mock yield control; the yield is recorded and nothing else happens
+    pub fn yield_control(&self, yield_data: &mut CmioYield) -> Result<()> {
+        self.yields.borrow_mut().push(*yield_data);
         Ok(())
     }
 
@@ -52,49 +88,128 @@ impl CmioIoDriver {
         self.tx_buf.len()
     }
 
+    /// Get the first `len` bytes of the RX buffer
+    pub fn rx_response(&self, len: usize) -> Result<&[u8]> {
+        if len > self.rx_buf.len() {
+            return Err(CmioError::InvalidArgument);
+        }
+        Ok(&self.rx_buf[..len])
+    }
+
+    /// Get the length of the most recent simulated response
+    pub fn last_response_len(&self) -> usize {
+        self.last_response_len
+    }
+
     /// Get the length of the RX buffer
     pub fn rx_len(&self) -> usize {
         self.rx_buf.len()
     }
 
     /// Mock send data via CMIO and receive a response.
-    /// This function simulates the host side of a vsock connection.
-    pub fn send_cmio(&mut self, tx_data: &[u8], _domain: u16) -> Result<Vec<u8>> {
+    /// This function simulates the host side of a vsock connection; `domain`
+    /// is only used as the reason of the (no-op) yield.
+    pub fn send_cmio(&mut self, tx_data: &[u8], domain: u16) -> Result<Vec<u8>> {
         if tx_data.len() > self.tx_len() {
             return Err(CmioError::InvalidArgument);
         }
+        let mut yield_data = CmioYield {
+            dev: HTIF_DEVICE_YIELD,
+            cmd: HTIF_YIELD_CMD_MANUAL,
+            reason: domain,
+            data: tx_data.len() as u32,
+        };
+        self.yield_control(&mut yield_data)?;
+        if !tx_data.is_empty() {
+            self.sent.push(tx_data.to_vec());
+        }
+
+        let response = self.simulate_host(tx_data);
+        check_response_len(response.len(), self.rx_len())?;
+        // Leave the response in the RX buffer, as the emulator would.
+        self.rx_buf[..response.len()].copy_from_slice(&response);
+        self.last_response_len = response.len();
+        Ok(response)
+    }
 
+    /// Produce the host's reply to `tx_data`
+    fn simulate_host(&mut self, tx_data: &[u8]) -> Vec<u8> {
+        if !tx_data.is_empty() && !self.data_replies.is_empty() {
+            return self.data_replies.remove(0);
+        }
         if !tx_data.is_empty() {
             if let Some(hdr) = VirtioVsockHdr::from_bytes(tx_data) {
                 return match hdr.op {
                     VSOCK_OP_RESPONSE => {
                         // Connection is established. Store response for the host.
                         self.pending_responses.insert(hdr.dst_port, tx_data.to_vec());
-                        Ok(Vec::new())
+                        Vec::new()
                     }
                     VSOCK_OP_RW => {
                         // For data coming from the guest, we can just acknowledge
-                        Ok(Vec::new())
+                        Vec::new()
                     }
                     VSOCK_OP_REQUEST => {
                         // Host is sending a request. Store it.
                         self.pending_requests.push(tx_data.to_vec());
-                        if let Some(response) = self.pending_responses.remove(&hdr.src_port) {
-                            Ok(response)
-                        } else {
-                            Ok(Vec::new())
-                        }
+                        self.pending_responses.remove(&hdr.src_port).unwrap_or_default()
                     }
-                    _ => Ok(Vec::new()),
+                    _ => Vec::new(),
                 };
             }
         }
 
         if !self.pending_requests.is_empty() {
-            return Ok(self.pending_requests.remove(0));
+            return self.pending_requests.remove(0);
         }
 
-        Ok(Vec::new())
+        Vec::new()
+    }
+}
+
+impl CmioIoDriver {
+    /// Mock automatic TX report; the data is recorded and no response is
+    /// expected.
+    pub fn report(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.tx_len() {
+            return Err(CmioError::InvalidArgument);
+        }
+        let mut yield_data = CmioYield {
+            dev: HTIF_DEVICE_YIELD,
+            cmd: HTIF_YIELD_CMD_AUTOMATIC,
+            reason: HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT,
+            data: data.len() as u32,
+        };
+        self.yield_control(&mut yield_data)?;
+        self.reports.push(data.to_vec());
+        Ok(())
+    }
+
+    /// Get the reports emitted so far, oldest first
+    pub fn reports(&self) -> &[Vec<u8>] {
+        &self.reports
+    }
+
+    /// Get the yields made so far, oldest first
+    pub fn yields(&self) -> Vec<CmioYield> {
+        self.yields.borrow().clone()
+    }
+
+    /// Get the non-empty data passed to `send_cmio` so far, oldest first
+    pub fn sent(&self) -> &[Vec<u8>] {
+        &self.sent
+    }
+
+    /// Queue `data` as the host's response to a later `send_cmio` that does
+    /// not itself carry a vsock packet, e.g. a poll with no data
+    pub fn push_response(&mut self, data: Vec<u8>) {
+        self.pending_requests.push(data);
+    }
+
+    /// Queue `data` as the host's response to a later `send_cmio` that does
+    /// carry data, in place of the simulated reply
+    pub fn push_data_reply(&mut self, data: Vec<u8>) {
+        self.data_replies.push(data);
     }
 }
 
@@ -102,4 +217,84 @@ impl Drop for CmioIoDriver {
     fn drop(&mut self) {
         // Nothing to do for the mock
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MIN_BUFFER_SIZE;
+
+    #[test]
+    fn tiny_buffers_are_rejected() {
+        assert!(matches!(
+            CmioIoDriver::with_buffer_sizes(MIN_BUFFER_SIZE - 1, 4096),
+            Err(CmioError::BufferTooSmall { .. })
+        ));
+        assert!(matches!(
+            CmioIoDriver::with_buffer_sizes(4096, 1),
+            Err(CmioError::BufferTooSmall { .. })
+        ));
+        assert!(CmioIoDriver::with_buffer_sizes(MIN_BUFFER_SIZE, MIN_BUFFER_SIZE).is_ok());
+    }
+
+    #[test]
+    fn new_with_timeout_never_times_out() {
+        let driver = CmioIoDriver::new_with_timeout(Duration::ZERO).unwrap();
+        assert_eq!((driver.tx_len(), driver.rx_len()), (4096, 4096));
+    }
+
+    #[test]
+    fn report_records_data_and_rejects_oversize() {
+        let mut driver = CmioIoDriver::with_buffer_sizes(64, 64).unwrap();
+        driver.report(b"first").unwrap();
+        driver.report(&[7; 64]).unwrap();
+        assert!(matches!(
+            driver.report(&[0; 65]),
+            Err(CmioError::InvalidArgument)
+        ));
+        assert_eq!(driver.reports(), [b"first".to_vec(), vec![7; 64]]);
+    }
+
+    #[test]
+    fn report_yields_an_automatic_tx_report() {
+        let mut driver = CmioIoDriver::new().unwrap();
+        driver.report(b"progress").unwrap();
+        let yields = driver.yields();
+        assert_eq!(yields.len(), 1);
+        assert_eq!(yields[0].dev, HTIF_DEVICE_YIELD);
+        assert_eq!(yields[0].cmd, HTIF_YIELD_CMD_AUTOMATIC);
+        assert_eq!(yields[0].reason, HTIF_YIELD_AUTOMATIC_REASON_TX_REPORT);
+        assert_eq!(yields[0].data, 8);
+
+        // A rejected report does not yield.
+        assert!(driver.report(&[0; 4097]).is_err());
+        assert_eq!(driver.yields().len(), 1);
+    }
+
+    #[test]
+    fn send_cmio_yields_manually_on_the_given_domain() {
+        let mut driver = CmioIoDriver::new().unwrap();
+        driver.send_cmio(b"data", crate::DOMAIN_VSOCK).unwrap();
+        let yields = driver.yields();
+        assert_eq!(yields.len(), 1);
+        assert_eq!(yields[0].cmd, HTIF_YIELD_CMD_MANUAL);
+        assert_eq!(yields[0].reason, crate::DOMAIN_VSOCK);
+        assert_eq!(yields[0].data, 4);
+    }
+
+    #[test]
+    fn send_cmio_trims_the_response_to_its_length() {
+        let mut driver = CmioIoDriver::with_buffer_sizes(64, 64).unwrap();
+        driver.push_response(vec![0xaa; 48]);
+        assert_eq!(driver.send_cmio(&[], 0).unwrap(), vec![0xaa; 48]);
+
+        // A shorter response leaves stale bytes in the RX buffer, but only
+        // the reported length is returned.
+        driver.push_response(vec![1, 2, 3]);
+        assert_eq!(driver.send_cmio(&[], 0).unwrap(), [1, 2, 3]);
+        assert_eq!(driver.last_response_len(), 3);
+        assert_eq!(driver.rx_slice()[3..48], [0xaa; 45]);
+
+        assert!(driver.send_cmio(&[], 0).unwrap().is_empty());
+        assert_eq!(driver.last_response_len(), 0);
+    }
+}
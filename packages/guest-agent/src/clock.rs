@@ -0,0 +1,64 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Source of time for the guest agent's deadlines and loop pacing.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+
+    /// Blocks for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock: [`Instant::now`] and [`thread::sleep`].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when advanced, so time-dependent behaviour can be
+/// driven without real delays. `sleep` advances it instead of blocking.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    /// Creates a mock clock starting at the current instant.
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
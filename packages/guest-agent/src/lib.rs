@@ -1,26 +1,153 @@
-use cmio::CmioIoDriver;
-use log::{error, info};
+mod clock;
+mod session;
+
+pub use clock::{Clock, MockClock, SystemClock};
+pub use session::{ConnectionSnapshot, SessionSnapshot};
+
+use cmio::{CmioError, CmioIoDriver, DOMAIN_VSOCK};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use vsock::{VsockAddr, VsockStream};
 use vsock_protocol::{
-    Packet, VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RST, VSOCK_OP_RW,
-    VSOCK_OP_SHUTDOWN,
+    op_name, CreditState, Framing, Hello, Packet, PacketRef, ParseError, RawFraming,
+    VirtioVsockHdr, FEATURE_CREDIT, FEATURE_SHUTDOWN_FLAGS, HDR_SIZE, MAX_PAYLOAD_SIZE,
+    PROTOCOL_VERSION, VSOCK_OP_CREDIT_REQUEST, VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_REQUEST,
+    VSOCK_OP_RESPONSE, VSOCK_OP_RST, VSOCK_OP_RW, VSOCK_OP_SHUTDOWN,
 };
 
-const CMIO_QUEUE_ID: u16 = 0x27;
 const RW_BUF_SIZE: usize = 4096;
 const LOOP_SLEEP_DURATION: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_PACKETS_PER_POLL: usize = 16;
+const DEFAULT_MAX_YIELD_FAILURES: u32 = 10;
+/// How long a connection the agent closed is remembered, so that a SHUTDOWN
+/// or RST from the runner that crossed ours is recognised rather than
+/// reported as traffic for an unknown connection.
+const CLOSE_LINGER: Duration = Duration::from_secs(60);
+/// Protocol features the guest agent advertises in its Hello.
+const GUEST_FEATURES: u32 = FEATURE_CREDIT | FEATURE_SHUTDOWN_FLAGS;
+
+/// Tunables for the guest agent's poll loop.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    /// Maximum number of RW packets forwarded to CMIO in a single pass over the
+    /// local streams. Connections are serviced round-robin across passes, so a
+    /// busy connection cannot starve the others or the inbound CMIO poll.
+    pub max_packets_per_poll: usize,
+    /// Maximum number of bytes read from a single local stream and forwarded
+    /// to CMIO per pass. It is further capped so that an RW packet, once
+    /// framed, fits the CMIO TX buffer. The runner's advertised credit lowers
+    /// it further still;
+    /// when its window is full the stream is not read at all until a credit
+    /// update arrives, leaving the data in the socket as backpressure.
+    pub max_forward_bytes: usize,
+    /// How long a local stream may go without producing data before the agent
+    /// closes it and sends an RST to the runner. Only reads count: data
+    /// arriving from CMIO does not extend the deadline. Passes where the
+    /// stream is not read because the send window is full do not count
+    /// towards it either. `None` disables the deadline.
+    pub read_timeout: Option<Duration>,
+    /// Encoding applied to packets on the CMIO channel. The runner must use
+    /// the same framing. Defaults to [`RawFraming`].
+    pub framing: Arc<dyn Framing>,
+    /// When set, data read from a local stream is held for up to this long
+    /// and sent as one larger RW packet together with later reads, trading
+    /// latency for fewer CMIO yields. Held data is flushed once the linger
+    /// expires, once `rw_coalesce_bytes` have accumulated, or when the stream
+    /// reaches EOF. `None` forwards every read immediately.
+    pub rw_linger: Option<Duration>,
+    /// Amount of held data that triggers an early flush when `rw_linger` is
+    /// set. Capped at the largest RW payload that fits the CMIO TX buffer.
+    pub rw_coalesce_bytes: usize,
+    /// CMIO domain the agent exchanges vsock packets on. Defaults to
+    /// [`DOMAIN_VSOCK`].
+    pub queue_id: u16,
+    /// Number of consecutive failed CMIO yields after which the agent stops
+    /// instead of retrying every loop iteration. `None` retries forever.
+    pub max_yield_failures: Option<u32>,
+    /// Time source for read timeouts, RW lingering and the loop's idle
+    /// sleep. Defaults to [`SystemClock`]; use [`MockClock`] to drive these
+    /// deterministically.
+    pub clock: Arc<dyn Clock>,
+    /// Number of consecutive loop iterations with open connections but no
+    /// packets in either direction after which the agent considers itself
+    /// stalled, logs a warning and takes `stall_action`. The count restarts
+    /// once the action has been taken. A connection that is idle but healthy
+    /// looks the same, so detection is opt-in: `None`, the default, disables
+    /// it.
+    pub stall_threshold: Option<u32>,
+    /// What to do when a stall is detected.
+    pub stall_action: StallAction,
+}
+
+/// Recovery taken by the guest agent when its loop stalls, e.g. because the
+/// runner keeps answering polls with empty responses while waiting for data
+/// the guest is itself waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StallAction {
+    /// Only log a warning.
+    #[default]
+    Warn,
+    /// Send a credit update on every open connection to prompt the runner.
+    CreditUpdate,
+    /// Stop the agent with an error.
+    Abort,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            max_packets_per_poll: DEFAULT_MAX_PACKETS_PER_POLL,
+            max_forward_bytes: RW_BUF_SIZE,
+            read_timeout: None,
+            framing: Arc::new(RawFraming),
+            rw_linger: None,
+            rw_coalesce_bytes: RW_BUF_SIZE,
+            queue_id: DOMAIN_VSOCK,
+            max_yield_failures: Some(DEFAULT_MAX_YIELD_FAILURES),
+            clock: Arc::new(SystemClock),
+            stall_threshold: None,
+            stall_action: StallAction::Warn,
+        }
+    }
+}
+
+/// A local stream the guest agent forwards CMIO traffic to.
+///
+/// Implemented for [`VsockStream`]; other implementations (for example an
+/// in-memory pipe) let the forwarding logic run without a vsock-capable host.
+pub trait LocalStream: Read + Write + Sized {
+    /// Connects to the local service at `cid:port`. The returned stream must be
+    /// nonblocking: the agent polls it and treats `WouldBlock` as "no data yet".
+    fn connect(cid: u32, port: u32) -> io::Result<Self>;
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
-struct ConnectionKey {
-    cid: u32,
-    port: u32,
+    /// Shuts down the read half, the write half, or both halves of the stream.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl LocalStream for VsockStream {
+    fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let stream = VsockStream::connect(&VsockAddr::new(cid, port))?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        VsockStream::shutdown(self, how)
+    }
+}
+
+/// Identifies a forwarded connection by the runner-side address it was
+/// opened from.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct ConnectionKey {
+    pub cid: u32,
+    pub port: u32,
 }
 
 impl From<&VirtioVsockHdr> for ConnectionKey {
@@ -32,60 +159,406 @@ impl From<&VirtioVsockHdr> for ConnectionKey {
     }
 }
 
-struct Connection {
-    stream: VsockStream,
+struct Connection<S> {
+    stream: S,
     request_hdr: VirtioVsockHdr,
+    credit: CreditState,
+    /// When data was last read from `stream` (or the connection was opened).
+    last_read: Instant,
+    /// The runner has shut down its receive side: data read from `stream` is
+    /// discarded, but EOF and errors still close the connection.
+    peer_no_rcv: bool,
+    /// The runner has shut down its send side: `stream`'s write half is closed.
+    peer_no_send: bool,
+    /// Data read from `stream` but held back for coalescing (see
+    /// [`AgentConfig::rw_linger`]). Counted as sent for credit only once
+    /// forwarded.
+    pending: Vec<u8>,
+    /// When the oldest byte in `pending` was read.
+    pending_since: Instant,
+}
+
+impl<S> Connection<S> {
+    /// Takes the held data as a single RW packet for the runner.
+    fn take_pending_packet(&mut self) -> Packet {
+        let payload = std::mem::take(&mut self.pending);
+        let rw_hdr = create_reply_header(
+            &self.request_hdr,
+            VSOCK_OP_RW,
+            payload.len() as u32,
+            &self.credit,
+        );
+        Packet::new(rw_hdr, payload)
+    }
 }
 
-struct ConnectionManager {
-    connections: HashMap<ConnectionKey, Connection>,
+/// Forwards vsock connections between CMIO and local streams of type `S`.
+///
+/// [`run_agent`] and friends create and run one of these; construct it
+/// directly to restore a migrated session before running it.
+pub struct ConnectionManager<S> {
+    connections: HashMap<ConnectionKey, Connection<S>>,
     cmio_driver: Arc<Mutex<CmioIoDriver>>,
+    config: AgentConfig,
+    /// The last connection serviced by `poll_vsock_connections`; the next pass
+    /// resumes after it.
+    last_polled: Option<ConnectionKey>,
+    /// Number of CMIO polls in a row whose yield failed.
+    yield_failures: u32,
+    /// Bytes received from CMIO that do not yet form a complete packet.
+    rx_pending: Vec<u8>,
+    /// Largest RW payload whose packet, once framed, fits the CMIO TX buffer.
+    max_rw_payload: usize,
+    /// Connections the agent closed whose SHUTDOWN or RST from the runner
+    /// may still be in flight, with when they were closed.
+    closing: HashMap<ConnectionKey, Instant>,
+    /// Whether any packet moved in either direction since the last
+    /// `check_stall`.
+    progressed: bool,
+    /// Number of loop iterations in a row without progress while
+    /// connections were open.
+    stalled_iterations: u32,
 }
 
-impl ConnectionManager {
-    fn new(cmio_driver: Arc<Mutex<CmioIoDriver>>) -> Self {
+impl<S: LocalStream> ConnectionManager<S> {
+    /// Creates a manager with no connections.
+    pub fn new(cmio_driver: Arc<Mutex<CmioIoDriver>>, config: AgentConfig) -> Self {
+        let tx_len = cmio_driver.lock().unwrap().tx_len();
+        let max_rw_payload = config
+            .framing
+            .max_decoded_len(tx_len)
+            .saturating_sub(HDR_SIZE)
+            .min(RW_BUF_SIZE);
+        if max_rw_payload == 0 {
+            warn!(
+                target: "guest",
+                "CMIO TX buffer of {} bytes cannot carry RW data with {:?}.",
+                tx_len, config.framing
+            );
+        }
         Self {
             connections: HashMap::new(),
             cmio_driver,
+            config,
+            last_polled: None,
+            yield_failures: 0,
+            rx_pending: Vec::new(),
+            max_rw_payload,
+            closing: HashMap::new(),
+            progressed: false,
+            stalled_iterations: 0,
         }
     }
 
+    /// Runs the agent's poll loop until it gives up.
+    pub fn run(mut self) -> Result<(), Box<dyn Error>> {
+        info!(target: "guest", "GUEST AGENT STARTED");
+
+        loop {
+            if let Err(e) = self.poll_vsock_connections() {
+                error!(target: "guest", "Error polling vsock connections: {}", e);
+            }
+
+            if let Err(e) = self.poll_cmio() {
+                error!(target: "guest", "Error polling CMIO: {}", e);
+            }
+
+            let yield_failures = self.consecutive_yield_failures();
+            if self
+                .config
+                .max_yield_failures
+                .is_some_and(|max| yield_failures >= max)
+            {
+                return Err(format!(
+                    "giving up after {} consecutive CMIO yield failures",
+                    yield_failures
+                )
+                .into());
+            }
+
+            if self.check_stall() {
+                warn!(
+                    target: "guest",
+                    "No CMIO or vsock traffic for {} iterations with {} open connections; taking {:?}.",
+                    self.config.stall_threshold.unwrap_or_default(),
+                    self.connections.len(),
+                    self.config.stall_action
+                );
+                match self.config.stall_action {
+                    StallAction::Warn => {}
+                    StallAction::CreditUpdate => self.send_credit_updates(),
+                    StallAction::Abort => {
+                        return Err("giving up: CMIO and vsock traffic stalled".into());
+                    }
+                }
+            }
+
+            self.config.clock.sleep(self.idle_sleep());
+        }
+    }
+
+    /// Returns how long the loop may sleep: [`LOOP_SLEEP_DURATION`], cut
+    /// short by the earliest linger deadline of any held data so that
+    /// `rw_linger` bounds the latency of a lone small write.
+    fn idle_sleep(&self) -> Duration {
+        let Some(linger) = self.config.rw_linger else {
+            return LOOP_SLEEP_DURATION;
+        };
+        let now = self.config.clock.now();
+        self.connections
+            .values()
+            .filter(|connection| !connection.pending.is_empty())
+            .map(|connection| (connection.pending_since + linger).saturating_duration_since(now))
+            .fold(LOOP_SLEEP_DURATION, Duration::min)
+    }
+
+    /// Returns the number of open connections.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the keys of the open connections, sorted.
+    pub fn connection_keys(&self) -> Vec<ConnectionKey> {
+        let mut keys: Vec<ConnectionKey> = self.connections.keys().copied().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Captures the connection metadata for migration. The local streams are
+    /// left open; see [`SessionSnapshot`] for what is not captured.
+    pub fn export_session(&self) -> SessionSnapshot {
+        let connections = self
+            .connections
+            .values()
+            .map(|connection| ConnectionSnapshot {
+                request_hdr: connection.request_hdr,
+                credit: connection.credit,
+                peer_no_rcv: connection.peer_no_rcv,
+                peer_no_send: connection.peer_no_send,
+                pending: connection.pending.clone(),
+            })
+            .collect();
+        SessionSnapshot {
+            connections,
+            rx_pending: self.rx_pending.clone(),
+        }
+    }
+
+    /// Restores a session captured by [`export_session`](Self::export_session),
+    /// typically into a freshly created manager.
+    ///
+    /// Each connection's local stream is reconnected through `S::connect`; a
+    /// connection whose service cannot be reached is reset towards the runner
+    /// and dropped. Read timeouts and linger deadlines restart from now. A
+    /// connection already open under the same key is replaced.
+    pub fn import_session(&mut self, snapshot: SessionSnapshot) {
+        self.rx_pending = snapshot.rx_pending;
+        for conn in snapshot.connections {
+            let key = ConnectionKey::from(&conn.request_hdr);
+            let stream = match S::connect(conn.request_hdr.dst_cid, conn.request_hdr.dst_port) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!(target: "guest", "Failed to reconnect migrated connection {:?}: {}", key, e);
+                    if let Err(e) = self.send_op_to_cmio(&conn.request_hdr, VSOCK_OP_RST) {
+                        error!(target: "guest", "Failed to send reset for {:?}: {}", key, e);
+                    }
+                    continue;
+                }
+            };
+            if conn.peer_no_send {
+                if let Err(e) = stream.shutdown(Shutdown::Write) {
+                    error!(target: "guest", "Failed to half-close vsock stream for {:?}: {}", key, e);
+                }
+            }
+            self.close_connection(&key);
+            let now = self.config.clock.now();
+            self.connections.insert(
+                key,
+                Connection {
+                    stream,
+                    request_hdr: conn.request_hdr,
+                    credit: conn.credit,
+                    last_read: now,
+                    peer_no_rcv: conn.peer_no_rcv,
+                    peer_no_send: conn.peer_no_send,
+                    pending: conn.pending,
+                    pending_since: now,
+                },
+            );
+            info!(target: "guest", "Restored migrated connection {:?}", key);
+        }
+    }
+
+    /// Returns the number of CMIO polls in a row whose yield failed. Reset by
+    /// the next successful poll.
+    pub fn consecutive_yield_failures(&self) -> u32 {
+        self.yield_failures
+    }
+
+    /// Ends a loop iteration for stall detection. Returns `true` when
+    /// `stall_threshold` iterations in a row have passed with open
+    /// connections and no packets in either direction; the count then
+    /// restarts.
+    fn check_stall(&mut self) -> bool {
+        if std::mem::take(&mut self.progressed) || self.connections.is_empty() {
+            self.stalled_iterations = 0;
+            return false;
+        }
+        self.stalled_iterations += 1;
+        match self.config.stall_threshold {
+            Some(threshold) if self.stalled_iterations >= threshold => {
+                self.stalled_iterations = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends a credit update on every open connection, prompting the runner
+    /// to resume a connection it believes is blocked.
+    fn send_credit_updates(&mut self) {
+        for key in self.connection_keys() {
+            if let Err(e) = self.send_credit_update(&key) {
+                error!(target: "guest", "Failed to send credit update for {:?}: {}", key, e);
+            }
+        }
+    }
+
+    fn send_credit_update(&mut self, key: &ConnectionKey) -> Result<(), Box<dyn Error>> {
+        let Some(connection) = self.connections.get(key) else {
+            return Ok(());
+        };
+        let update_hdr = create_reply_header(
+            &connection.request_hdr,
+            VSOCK_OP_CREDIT_UPDATE,
+            0,
+            &connection.credit,
+        );
+        let mut hdr_buf = [0; HDR_SIZE];
+        update_hdr.write_to_slice(&mut hdr_buf);
+        self.transmit(&hdr_buf)?;
+        Ok(())
+    }
+
+    /// Sends `data` to CMIO, buffering the reply to the yield for
+    /// [`process_rx_pending`](Self::process_rx_pending): the runner may answer
+    /// any yield, not only an empty poll.
+    fn transmit(&mut self, data: &[u8]) -> Result<(), CmioError> {
+        let reply = self
+            .cmio_driver
+            .lock()
+            .unwrap()
+            .send_cmio(&self.config.framing.encode(data), self.config.queue_id)?;
+        buffer_cmio_reply(&*self.config.framing, &mut self.rx_pending, &reply);
+        Ok(())
+    }
+
     fn poll_cmio(&mut self) -> Result<(), Box<dyn Error>> {
         let cmio_bytes = match self
             .cmio_driver
             .lock()
             .unwrap()
-            .send_cmio(&[], CMIO_QUEUE_ID)
+            .send_cmio(&[], self.config.queue_id)
         {
-            Ok(bytes) => bytes,
+            Ok(bytes) => {
+                self.yield_failures = 0;
+                bytes
+            }
             Err(e) => {
+                if matches!(e, CmioError::YieldFailed { .. }) {
+                    self.yield_failures += 1;
+                }
                 error!(target: "guest", "Error polling CMIO for request: {}", e);
                 return Ok(());
             }
         };
 
-        if cmio_bytes.is_empty() {
-            return Ok(());
-        }
+        buffer_cmio_reply(&*self.config.framing, &mut self.rx_pending, &cmio_bytes);
+        self.process_rx_pending()
+    }
 
-        let packet = match Packet::from_bytes(&cmio_bytes) {
-            Ok(p) => p,
-            Err(_) => {
-                info!(target: "guest", "Incomplete packet from CMIO, ignoring.");
-                return Ok(());
+    /// Handles every complete packet buffered from CMIO replies, keeping any
+    /// trailing partial one. Replies to the yields made while handling a
+    /// packet are buffered too and handled in the same call.
+    fn process_rx_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        // A packet may arrive split across several responses; buffer bytes
+        // until a whole packet is available. A header that cannot be valid
+        // means the buffer is out of step with the packet boundaries, so it
+        // is discarded rather than waited on.
+        loop {
+            if let Some(hdr) = VirtioVsockHdr::from_bytes(&self.rx_pending) {
+                if let Err(e) = hdr.validate() {
+                    error!(
+                        target: "guest",
+                        "Invalid CMIO packet header ({}), discarding {} buffered bytes.",
+                        e,
+                        self.rx_pending.len()
+                    );
+                    self.rx_pending.clear();
+                    return Ok(());
+                }
             }
-        };
-
-        self.handle_cmio_packet(packet)
+            match Packet::from_bytes_with_len(&self.rx_pending) {
+                Ok((packet, len)) => {
+                    self.rx_pending.drain(..len);
+                    self.progressed = true;
+                    self.handle_cmio_packet(packet)?;
+                }
+                Err(ParseError::ShortPayload { expected, .. })
+                    if expected > MAX_PAYLOAD_SIZE as usize =>
+                {
+                    error!(
+                        target: "guest",
+                        "CMIO packet announces a {} byte payload, discarding {} buffered bytes.",
+                        expected,
+                        self.rx_pending.len()
+                    );
+                    self.rx_pending.clear();
+                    return Ok(());
+                }
+                Err(e) => {
+                    debug!(
+                        target: "guest",
+                        "Waiting for more CMIO data ({} bytes buffered): {}",
+                        self.rx_pending.len(),
+                        e
+                    );
+                    return Ok(());
+                }
+            }
+        }
     }
 
     fn handle_cmio_packet(&mut self, packet: Packet) -> Result<(), Box<dyn Error>> {
         let (hdr, payload) = packet.into_parts();
-        info!(target: "guest", "GUEST: RECEIVED NEW PACKET FROM CMIO\n {:?}", hdr);
+        info!(target: "guest", "GUEST: RECEIVED NEW PACKET FROM CMIO: {}", hdr);
         let key = ConnectionKey::from(&hdr);
 
+        if let Some(connection) = self.connections.get_mut(&key) {
+            connection.credit.update_from_hdr(&hdr);
+        }
+
+        if hdr.op != VSOCK_OP_REQUEST
+            && !self.connections.contains_key(&key)
+            && self.closing.contains_key(&key)
+        {
+            // Both sides closed at once and the runner's close crossed ours:
+            // that completes the close. Anything else still in flight for the
+            // connection is dropped without reply.
+            if matches!(hdr.op, VSOCK_OP_SHUTDOWN | VSOCK_OP_RST) {
+                self.closing.remove(&key);
+                debug!(target: "guest", "Received {} for closing connection {:?}, close complete.", op_name(hdr.op), key);
+            } else {
+                debug!(target: "guest", "Dropping {} for closing connection {:?}.", op_name(hdr.op), key);
+            }
+            return Ok(());
+        }
+
         match hdr.op {
-            VSOCK_OP_REQUEST => self.handle_new_connection_request(hdr)?,
+            VSOCK_OP_REQUEST => {
+                self.closing.remove(&key);
+                self.handle_new_connection_request(hdr, &payload)?
+            }
             VSOCK_OP_RW => {
                 if let Some(connection) = self.connections.get_mut(&key) {
                     if !payload.is_empty() {
@@ -95,21 +568,30 @@ impl ConnectionManager {
                             payload.len(),
                             key
                         );
-                        if let Err(e) = connection.stream.write_all(&payload) {
-                            error!(target: "guest", "Failed to write to vsock stream for {:?}: {}", key, e);
+                        match connection.stream.write_all(&payload) {
+                            Ok(()) => connection.credit.record_forwarded(payload.len() as u32),
+                            Err(e) => error!(
+                                target: "guest",
+                                "Failed to write to vsock stream for {:?}: {}",
+                                key,
+                                e
+                            ),
                         }
                     }
                 } else {
                     info!(target: "guest", "Received OP_RW for unknown connection: {:?}. Ignoring.", key);
                 }
             }
-            VSOCK_OP_RST | VSOCK_OP_SHUTDOWN => {
-                info!(target: "guest", "Received OP {} for {:?}, closing connection.", hdr.op, key);
-                if let Some(conn) = self.connections.remove(&key) {
-                    let _ = conn.stream.shutdown(std::net::Shutdown::Both);
-                }
+            VSOCK_OP_CREDIT_UPDATE => {
+                debug!(target: "guest", "Credit update for {:?}: {}", key, hdr);
+            }
+            VSOCK_OP_CREDIT_REQUEST => self.send_credit_update(&key)?,
+            VSOCK_OP_SHUTDOWN => self.handle_shutdown(&key, &hdr),
+            VSOCK_OP_RST => {
+                info!(target: "guest", "Received {} for {:?}, closing connection.", op_name(hdr.op), key);
+                self.close_connection(&key);
             }
-            _ => info!(target: "guest", "Received unhandled OP {} from CMIO. Ignoring.", hdr.op),
+            _ => info!(target: "guest", "Received unhandled {} from CMIO. Ignoring.", hdr),
         }
 
         Ok(())
@@ -118,24 +600,63 @@ impl ConnectionManager {
     fn handle_new_connection_request(
         &mut self,
         request_hdr: VirtioVsockHdr,
+        payload: &[u8],
     ) -> Result<(), Box<dyn Error>> {
         let key = ConnectionKey::from(&request_hdr);
+
+        // The request's payload carries the runner's Hello; runners that
+        // predate the version exchange send none and are assumed compatible.
+        let local_hello = Hello::new(GUEST_FEATURES);
+        if payload.is_empty() {
+            debug!(
+                target: "guest",
+                "Connection request for {:?} carries no protocol version, assuming {}.",
+                key, PROTOCOL_VERSION
+            );
+        } else {
+            let Some(peer_hello) = Hello::from_bytes(payload) else {
+                error!(target: "guest", "Refusing connection request for {:?}: malformed protocol hello.", key);
+                return self.send_op_to_cmio(&request_hdr, VSOCK_OP_RST);
+            };
+            if let Err(e) = local_hello.check_compatible(&peer_hello) {
+                error!(target: "guest", "Refusing connection request for {:?}: {}", key, e);
+                return self.send_op_to_cmio(&request_hdr, VSOCK_OP_RST);
+            }
+            debug!(
+                target: "guest",
+                "Runner for {:?} speaks protocol {}, common features {:#x}.",
+                key,
+                peer_hello.version,
+                local_hello.common_features(&peer_hello)
+            );
+        }
+
         if self.connections.contains_key(&key) {
-            info!(target: "guest", "Connection request for existing key {:?}, ignoring.", key);
-            return Ok(());
+            // A duplicate REQUEST (e.g. a retransmit) for an established
+            // connection is answered again without reconnecting the local stream.
+            info!(target: "guest", "Connection request for existing key {:?}, re-sending response.", key);
+            return self.send_to_cmio(&request_hdr, VSOCK_OP_RESPONSE, &local_hello.to_bytes());
         }
 
         info!(target: "guest", "ATTEMPTING TO CONNECT FOR {:?}", key);
-        match VsockStream::connect(&VsockAddr::new(request_hdr.dst_cid, request_hdr.dst_port)) {
+        match S::connect(request_hdr.dst_cid, request_hdr.dst_port) {
             Ok(stream) => {
                 info!(target: "guest", "Connection to guest vsock successful for {:?}", key);
-                stream.set_nonblocking(true)?;
-                self.send_op_to_cmio(&request_hdr, VSOCK_OP_RESPONSE)?;
+                self.send_to_cmio(&request_hdr, VSOCK_OP_RESPONSE, &local_hello.to_bytes())?;
+                let mut credit = CreditState::new(RW_BUF_SIZE as u32);
+                credit.update_from_hdr(&request_hdr);
+                let now = self.config.clock.now();
                 self.connections.insert(
                     key,
                     Connection {
                         stream,
                         request_hdr,
+                        credit,
+                        last_read: now,
+                        peer_no_rcv: false,
+                        peer_no_send: false,
+                        pending: Vec::new(),
+                        pending_since: now,
                     },
                 );
             }
@@ -150,61 +671,161 @@ impl ConnectionManager {
     fn poll_vsock_connections(&mut self) -> Result<(), Box<dyn Error>> {
         let mut read_buf = [0u8; RW_BUF_SIZE];
         let mut to_remove = Vec::new();
-        let mut packets_to_send = Vec::new();
+        let mut packets_sent = 0;
         let mut resets_to_send = Vec::new();
         let mut shutdowns_to_send = Vec::new();
 
-        for (key, connection) in &mut self.connections {
-            match connection.stream.read(&mut read_buf) {
+        let now = self.config.clock.now();
+        self.closing
+            .retain(|_, closed_at| now.saturating_duration_since(*closed_at) < CLOSE_LINGER);
+
+        let mut keys = self.connection_keys();
+        if let Some(last) = self.last_polled {
+            let start = keys.partition_point(|key| *key <= last);
+            keys.rotate_left(start);
+        }
+
+        for key in &keys {
+            if packets_sent >= self.config.max_packets_per_poll {
+                debug!(
+                    target: "guest",
+                    "Reached burst limit of {} packets, deferring remaining connections.",
+                    self.config.max_packets_per_poll
+                );
+                break;
+            }
+            self.last_polled = Some(*key);
+            let Some(connection) = self.connections.get_mut(key) else {
+                continue;
+            };
+
+            // Held data and the new read must fit in a single RW packet.
+            // Data the runner will not receive is discarded without limit.
+            let mut window = if connection.peer_no_rcv {
+                RW_BUF_SIZE
+            } else {
+                self.config
+                    .max_forward_bytes
+                    .min(self.max_rw_payload.saturating_sub(connection.pending.len()))
+            };
+            let mut eof = false;
+
+            // Peers that never advertise a buffer (buf_alloc == 0) predate
+            // credit accounting and are not flow controlled.
+            if !connection.peer_no_rcv && connection.credit.peer_buf_alloc() > 0 {
+                let peer_free = connection.credit.peer_free() as usize;
+                window = window.min(peer_free.saturating_sub(connection.pending.len()));
+            }
+            let read_result = if window == 0 {
+                debug!(target: "guest", "Send window full for {:?}, not reading local stream.", key);
+                Err(std::io::ErrorKind::WouldBlock.into())
+            } else {
+                connection.stream.read(&mut read_buf[..window])
+            };
+            let now = self.config.clock.now();
+
+            match read_result {
                 Ok(0) => {
                     info!(target: "guest", "Vsock stream closed by peer for {:?}.", key);
                     shutdowns_to_send.push(connection.request_hdr);
                     to_remove.push(*key);
+                    eof = true;
+                }
+                Ok(n) if connection.peer_no_rcv => {
+                    connection.last_read = now;
+                    debug!(
+                        target: "guest",
+                        "Discarding {} bytes from vsock for {:?}: runner stopped receiving.",
+                        n, key
+                    );
+                }
+                Ok(n) if self.config.rw_linger.is_some() => {
+                    connection.last_read = now;
+                    debug!(target: "guest", "Holding {} bytes from vsock for {:?}.", n, key);
+                    if connection.pending.is_empty() {
+                        connection.pending_since = now;
+                    }
+                    connection.pending.extend_from_slice(&read_buf[..n]);
                 }
                 Ok(n) => {
+                    connection.last_read = now;
                     let data = &read_buf[..n];
                     info!(
                         target: "guest",
                         "Received {} bytes from vsock for\n {:?}, forwarding to CMIO.",
                         n, key
                     );
-                    let rw_hdr =
-                        create_reply_header(&connection.request_hdr, VSOCK_OP_RW, n as u32);
-                    let packet_to_cmio = Packet::new(rw_hdr, data.to_vec());
-                    packets_to_send.push(packet_to_cmio);
-
-                    info!(
-                        target: "guest",
-                        "GUEST: ECHOING {} BYTES BACK TO VSOCK FOR\n {:?}",
-                        n, key
+                    let rw_hdr = create_reply_header(
+                        &connection.request_hdr,
+                        VSOCK_OP_RW,
+                        n as u32,
+                        &connection.credit,
                     );
-                    if let Err(e) = connection.stream.write_all(data) {
-                        error!(target: "guest", "Failed to echo to vsock stream for {:?}: {}", key, e);
+                    // Frame straight over the read buffer; no intermediate copy.
+                    let packet_to_cmio = PacketRef::new(rw_hdr, data);
+                    let packet_bytes = packet_to_cmio.to_bytes();
+                    match self.cmio_driver.lock().unwrap().send_cmio(
+                        &self.config.framing.encode(&packet_bytes),
+                        self.config.queue_id,
+                    ) {
+                        Ok(reply) => {
+                            connection.credit.record_sent(n as u32);
+                            buffer_cmio_reply(&*self.config.framing, &mut self.rx_pending, &reply);
+                        }
+                        Err(e) => {
+                            error!(target: "guest", "Failed to forward data to CMIO for {:?}: {}", key, e)
+                        }
+                    }
+                    packets_sent += 1;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    // A stream that was not read this pass is not starved.
+                    if let Some(timeout) = self.config.read_timeout.filter(|_| window > 0) {
+                        if now.saturating_duration_since(connection.last_read) >= timeout {
+                            info!(
+                                target: "guest",
+                                "No data from vsock stream for {:?} in {:?}, resetting.",
+                                key, timeout
+                            );
+                            resets_to_send.push(connection.request_hdr);
+                            to_remove.push(*key);
+                        }
                     }
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
                 Err(e) => {
                     error!(target: "guest", "Error reading from vsock stream for {:?}: {}", key, e);
                     resets_to_send.push(connection.request_hdr);
                     to_remove.push(*key);
                 }
             }
-        }
 
-        for packet in packets_to_send {
-            if let Err(e) = self
-                .cmio_driver
-                .lock()
-                .unwrap()
-                .send_cmio(&packet.to_bytes(), CMIO_QUEUE_ID)
-            {
-                let (hdr, _) = packet.into_parts();
-                error!(
-                    target: "guest",
-                    "Failed to forward data to CMIO for {:?}: {}",
-                    ConnectionKey::from(&hdr),
-                    e
-                );
+            if let Some(linger) = self.config.rw_linger {
+                let flush_due = eof
+                    || connection.pending.len()
+                        >= self.config.rw_coalesce_bytes.min(self.max_rw_payload)
+                    || now.saturating_duration_since(connection.pending_since) >= linger;
+                if !connection.pending.is_empty() && flush_due {
+                    let packet = connection.take_pending_packet();
+                    info!(
+                        target: "guest",
+                        "Forwarding {} coalesced bytes to CMIO for {:?}.",
+                        packet.payload().len(),
+                        key
+                    );
+                    match self.cmio_driver.lock().unwrap().send_cmio(
+                        &self.config.framing.encode(&packet.to_bytes()),
+                        self.config.queue_id,
+                    ) {
+                        Ok(reply) => {
+                            connection.credit.record_sent(packet.payload().len() as u32);
+                            buffer_cmio_reply(&*self.config.framing, &mut self.rx_pending, &reply);
+                        }
+                        Err(e) => {
+                            error!(target: "guest", "Failed to forward data to CMIO for {:?}: {}", key, e)
+                        }
+                    }
+                    packets_sent += 1;
+                }
             }
         }
 
@@ -230,68 +851,152 @@ impl ConnectionManager {
             }
         }
 
+        self.progressed |= packets_sent > 0 || !to_remove.is_empty();
         for key in to_remove {
-            if let Some(conn) = self.connections.remove(&key) {
-                let _ = conn.stream.shutdown(std::net::Shutdown::Both);
-            }
-            info!(target: "guest", "Removed connection {:?}", key);
+            self.closing.insert(key, self.config.clock.now());
+            self.close_connection(&key);
         }
         Ok(())
     }
 
-    fn send_op_to_cmio(&self, request_hdr: &VirtioVsockHdr, op: u16) -> Result<(), Box<dyn Error>> {
-        let op_str = match op {
-            VSOCK_OP_RESPONSE => "VSOCK_OP_RESPONSE",
-            VSOCK_OP_RST => "VSOCK_OP_RST",
-            VSOCK_OP_SHUTDOWN => "VSOCK_OP_SHUTDOWN",
-            _ => "UNKNOWN_OP",
+    /// Applies a `VSOCK_OP_SHUTDOWN` from the runner.
+    ///
+    /// A send-only shutdown half-closes the local stream so the service sees
+    /// EOF while its replies are still forwarded; after a receive-only
+    /// shutdown the local stream's data is discarded, while its EOF or errors
+    /// still close the connection. Once both directions are shut, or for a
+    /// shutdown without flags, the connection is closed.
+    fn handle_shutdown(&mut self, key: &ConnectionKey, hdr: &VirtioVsockHdr) {
+        let (no_rcv, no_send) = hdr.shutdown_mode();
+        let Some(connection) = self.connections.get_mut(key) else {
+            debug!(target: "guest", "Received {} for unknown connection {:?}. Ignoring.", op_name(hdr.op), key);
+            return;
         };
 
+        connection.peer_no_rcv |= no_rcv;
+        connection.peer_no_send |= no_send;
+        let full_close =
+            (!no_rcv && !no_send) || (connection.peer_no_rcv && connection.peer_no_send);
+        if !full_close {
+            if no_send {
+                info!(target: "guest", "Runner finished sending for {:?}, half-closing local stream.", key);
+                if let Err(e) = connection.stream.shutdown(Shutdown::Write) {
+                    error!(target: "guest", "Failed to half-close vsock stream for {:?}: {}", key, e);
+                }
+            }
+            if no_rcv {
+                info!(target: "guest", "Runner stopped receiving for {:?}, discarding local stream data.", key);
+                connection.pending.clear();
+            }
+            return;
+        }
+
+        info!(target: "guest", "Received {} for {:?}, closing connection.", op_name(hdr.op), key);
+        self.close_connection(key);
+    }
+
+    /// Tears down the connection for `key`, shutting down its local stream.
+    ///
+    /// Teardown is idempotent: a connection can be closed from several places
+    /// (RST, SHUTDOWN, read-zero, read error), and only the first call has any
+    /// effect.
+    fn close_connection(&mut self, key: &ConnectionKey) {
+        match self.connections.remove(key) {
+            Some(conn) => {
+                let _ = conn.stream.shutdown(Shutdown::Both);
+                info!(target: "guest", "Removed connection {:?}", key);
+            }
+            None => {
+                debug!(target: "guest", "Connection {:?} already closed, ignoring teardown.", key);
+            }
+        }
+    }
+
+    fn send_op_to_cmio(
+        &mut self,
+        request_hdr: &VirtioVsockHdr,
+        op: u16,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_to_cmio(request_hdr, op, &[])
+    }
+
+    /// Sends a reply with the given op and payload to CMIO, advertising the
+    /// connection's credit, or a fresh receive buffer if it is not open.
+    fn send_to_cmio(
+        &mut self,
+        request_hdr: &VirtioVsockHdr,
+        op: u16,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
         info!(
             target: "guest",
             "Sending {} to CMIO for {:?}",
-            op_str,
+            op_name(op),
             ConnectionKey::from(request_hdr)
         );
-        let reply_hdr = create_reply_header(request_hdr, op, 0);
-        let packet = Packet::new(reply_hdr, vec![]);
-        self.cmio_driver
-            .lock()
-            .unwrap()
-            .send_cmio(&packet.to_bytes(), CMIO_QUEUE_ID)?;
+        let key = ConnectionKey::from(request_hdr);
+        let credit = self
+            .connections
+            .get(&key)
+            .map_or_else(|| CreditState::new(RW_BUF_SIZE as u32), |c| c.credit);
+        let reply_hdr = create_reply_header(request_hdr, op, payload.len() as u32, &credit);
+        let packet = PacketRef::new(reply_hdr, payload);
+        self.transmit(&packet.to_bytes())?;
         Ok(())
     }
 }
 
-fn create_reply_header(request_hdr: &VirtioVsockHdr, op: u16, len: u32) -> VirtioVsockHdr {
-    VirtioVsockHdr {
-        src_cid: request_hdr.dst_cid,
-        dst_cid: request_hdr.src_cid,
-        src_port: request_hdr.dst_port,
-        dst_port: request_hdr.src_port,
-        len,
-        type_: request_hdr.type_,
-        op,
-        flags: 0,
-        buf_alloc: request_hdr.buf_alloc,
-        fwd_cnt: 0,
+/// Decodes a CMIO reply and appends it to the bytes awaiting reassembly.
+fn buffer_cmio_reply(framing: &dyn Framing, rx_pending: &mut Vec<u8>, reply: &[u8]) {
+    if reply.is_empty() {
+        return;
+    }
+    match framing.decode(reply) {
+        Ok(bytes) => rx_pending.extend_from_slice(&bytes),
+        Err(e) => error!(target: "guest", "Failed to decode CMIO framing: {}", e),
     }
 }
 
+/// Builds a header answering `request_hdr`, advertising the guest's receive
+/// buffer and forward count from `credit` so the runner can account for it.
+fn create_reply_header(
+    request_hdr: &VirtioVsockHdr,
+    op: u16,
+    len: u32,
+    credit: &CreditState,
+) -> VirtioVsockHdr {
+    VirtioVsockHdr::builder()
+        .src(request_hdr.dst_cid, request_hdr.dst_port)
+        .dst(request_hdr.src_cid, request_hdr.src_port)
+        .len(len)
+        .type_(request_hdr.type_)
+        .op(op)
+        .buf_alloc(credit.buf_alloc())
+        .fwd_cnt(credit.fwd_cnt())
+        .build()
+}
+
 /// Runs the main logic of the guest agent.
 pub fn run_agent(cmio_driver: Arc<Mutex<CmioIoDriver>>) -> Result<(), Box<dyn Error>> {
-    info!(target: "guest", "GUEST AGENT STARTED");
-    let mut manager = ConnectionManager::new(cmio_driver);
-
-    loop {
-        if let Err(e) = manager.poll_vsock_connections() {
-            error!(target: "guest", "Error polling vsock connections: {}", e);
-        }
+    run_agent_with_config(cmio_driver, AgentConfig::default())
+}
 
-        if let Err(e) = manager.poll_cmio() {
-            error!(target: "guest", "Error polling CMIO: {}", e);
-        }
+/// Runs the main logic of the guest agent with the given configuration.
+pub fn run_agent_with_config(
+    cmio_driver: Arc<Mutex<CmioIoDriver>>,
+    config: AgentConfig,
+) -> Result<(), Box<dyn Error>> {
+    run_agent_with_stream::<VsockStream>(cmio_driver, config)
+}
 
-        thread::sleep(LOOP_SLEEP_DURATION);
-    }
+/// Runs the main logic of the guest agent, connecting to local services
+/// through `S` instead of vsock.
+pub fn run_agent_with_stream<S: LocalStream>(
+    cmio_driver: Arc<Mutex<CmioIoDriver>>,
+    config: AgentConfig,
+) -> Result<(), Box<dyn Error>> {
+    ConnectionManager::<S>::new(cmio_driver, config).run()
 }
+
+#[cfg(test)]
+mod tests;
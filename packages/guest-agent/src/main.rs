@@ -7,6 +7,10 @@ use std::io::Write;
 use std::process;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long to wait for the CMIO device to finish setting up.
+const DEVICE_SETUP_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
     println!("Starting Guest Agent");
@@ -30,7 +34,13 @@ fn main() {
         .init();
 
     info!("Starting Guest Agent");
-    let driver = Arc::new(Mutex::new(CmioIoDriver::new().unwrap()));
+    let driver = match CmioIoDriver::new_with_timeout(DEVICE_SETUP_TIMEOUT) {
+        Ok(driver) => Arc::new(Mutex::new(driver)),
+        Err(e) => {
+            error!("Failed to initialize CMIO driver: {}", e);
+            process::exit(1);
+        }
+    };
 
     if let Err(e) = run_agent(driver) {
         error!("Agent failed: {}", e);
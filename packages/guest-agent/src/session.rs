@@ -0,0 +1,40 @@
+use vsock_protocol::{CreditState, VirtioVsockHdr};
+
+/// Transport state of a [`ConnectionManager`](crate::ConnectionManager),
+/// captured for migrating a running guest.
+///
+/// Only metadata is captured; the local streams are not. After
+/// [`import_session`](crate::ConnectionManager::import_session) the manager
+/// reconnects each local service itself, so the caller must make sure those
+/// services are listening on the destination before importing. The CMIO
+/// driver and [`AgentConfig`](crate::AgentConfig) are not part of the
+/// session either and are supplied when creating the destination manager.
+///
+/// Connections the agent has already closed are not captured: a SHUTDOWN or
+/// RST from the runner that crosses the migration is treated on the
+/// destination as traffic for an unknown connection and ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// The open connections, in no particular order.
+    pub connections: Vec<ConnectionSnapshot>,
+    /// Bytes received from CMIO that did not yet form a complete packet.
+    pub rx_pending: Vec<u8>,
+}
+
+/// Metadata of one forwarded connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionSnapshot {
+    /// The runner's `VSOCK_OP_REQUEST` header that opened the connection. Its
+    /// destination is the local service to reconnect to.
+    pub request_hdr: VirtioVsockHdr,
+    /// Credit accounting in both directions.
+    pub credit: CreditState,
+    /// The runner has shut down its receive side.
+    pub peer_no_rcv: bool,
+    /// The runner has shut down its send side.
+    pub peer_no_send: bool,
+    /// Data read from the local stream but not yet forwarded to the runner.
+    pub pending: Vec<u8>,
+}
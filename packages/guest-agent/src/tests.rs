@@ -0,0 +1,698 @@
+use super::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use vsock_protocol::{VSOCK_SHUTDOWN_RCV, VSOCK_SHUTDOWN_SEND, VSOCK_TYPE_STREAM};
+
+const RUNNER_CID: u32 = 2;
+const GUEST_CID: u32 = 3;
+const SERVICE_PORT: u32 = 8080;
+
+/// One end of an in-memory local stream; the test keeps a clone to play the
+/// local service.
+#[derive(Clone, Default)]
+struct MemStream(Rc<RefCell<MemState>>);
+
+#[derive(Default)]
+struct MemState {
+    /// Bytes the service has written, waiting to be read by the agent.
+    to_agent: VecDeque<u8>,
+    /// Bytes the agent has written to the service.
+    from_agent: Vec<u8>,
+    /// The service has closed its write half: reads return EOF once
+    /// `to_agent` is drained.
+    eof: bool,
+    shutdowns: Vec<Shutdown>,
+    /// Number of times the agent has connected to the service.
+    connects: usize,
+}
+
+thread_local! {
+    /// Services reachable through `MemStream::connect`, by port.
+    static SERVICES: RefCell<HashMap<u32, MemStream>> = RefCell::new(HashMap::new());
+}
+
+impl MemStream {
+    /// Makes a service reachable on `port` and returns its end.
+    fn listen(port: u32) -> Self {
+        let stream = Self::default();
+        SERVICES.with(|services| services.borrow_mut().insert(port, stream.clone()));
+        stream
+    }
+
+    fn send(&self, data: &[u8]) {
+        self.0.borrow_mut().to_agent.extend(data);
+    }
+
+    fn received(&self) -> Vec<u8> {
+        self.0.borrow().from_agent.clone()
+    }
+
+    fn close(&self) {
+        self.0.borrow_mut().eof = true;
+    }
+
+    fn shutdowns(&self) -> Vec<Shutdown> {
+        self.0.borrow().shutdowns.clone()
+    }
+
+    /// Number of bytes the service has written that the agent has not read.
+    fn unread(&self) -> usize {
+        self.0.borrow().to_agent.len()
+    }
+
+    fn connects(&self) -> usize {
+        self.0.borrow().connects
+    }
+}
+
+impl Read for MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        if state.to_agent.is_empty() {
+            return if state.eof {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+        let n = buf.len().min(state.to_agent.len());
+        for (dst, src) in buf.iter_mut().zip(state.to_agent.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().from_agent.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStream for MemStream {
+    fn connect(_cid: u32, port: u32) -> io::Result<Self> {
+        let stream = SERVICES
+            .with(|services| services.borrow().get(&port).cloned())
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+        stream.0.borrow_mut().connects += 1;
+        Ok(stream)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.borrow_mut().shutdowns.push(how);
+        Ok(())
+    }
+}
+
+struct Harness {
+    manager: ConnectionManager<MemStream>,
+    driver: Arc<Mutex<CmioIoDriver>>,
+}
+
+impl Harness {
+    fn new(config: AgentConfig) -> Self {
+        let driver = Arc::new(Mutex::new(CmioIoDriver::new().unwrap()));
+        let manager = ConnectionManager::new(driver.clone(), config);
+        Self { manager, driver }
+    }
+
+    /// Delivers `data` to the agent as the runner's answer to its next poll.
+    fn push_from_runner(&self, data: Vec<u8>) {
+        self.driver.lock().unwrap().push_response(data);
+    }
+
+    /// Delivers a packet from the runner's `port` and lets the agent poll it.
+    fn runner_sends(&mut self, op: u16, port: u32, flags: u32, payload: &[u8]) {
+        let hdr = runner_hdr(op, port, payload.len() as u32, flags);
+        self.push_from_runner(Packet::new(hdr, payload.to_vec()).to_bytes());
+        self.manager.poll_cmio().unwrap();
+    }
+
+    /// Opens a connection from the runner's `port` to the service on
+    /// `service_port`, returning the service's end.
+    fn open(&mut self, port: u32, service_port: u32) -> MemStream {
+        let service = MemStream::listen(service_port);
+        let hdr = VirtioVsockHdr::builder()
+            .src(RUNNER_CID, port)
+            .dst(GUEST_CID, service_port)
+            .type_stream()
+            .op(VSOCK_OP_REQUEST)
+            .build();
+        self.push_from_runner(Packet::new(hdr, vec![]).to_bytes());
+        self.manager.poll_cmio().unwrap();
+        service
+    }
+
+    /// Packets the agent has sent to the runner so far.
+    fn sent(&self) -> Vec<Packet> {
+        self.driver
+            .lock()
+            .unwrap()
+            .sent()
+            .iter()
+            .map(|bytes| Packet::from_bytes(bytes).unwrap())
+            .collect()
+    }
+
+    fn sent_ops(&self) -> Vec<u16> {
+        self.sent().iter().map(|packet| packet.hdr().op).collect()
+    }
+}
+
+fn runner_hdr(op: u16, port: u32, len: u32, flags: u32) -> VirtioVsockHdr {
+    VirtioVsockHdr::builder()
+        .src(RUNNER_CID, port)
+        .dst(GUEST_CID, SERVICE_PORT)
+        .len(len)
+        .type_stream()
+        .op(op)
+        .flags(flags)
+        .build()
+}
+
+#[test]
+fn request_response_and_rw_round_trip() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = MemStream::listen(SERVICE_PORT);
+    let hello = Hello::new(0).to_bytes();
+    h.runner_sends(VSOCK_OP_REQUEST, 1000, 0, &hello);
+
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    let response = &sent[0];
+    assert_eq!(response.hdr().op, VSOCK_OP_RESPONSE);
+    assert_eq!(
+        (response.hdr().src_port, response.hdr().dst_port),
+        (SERVICE_PORT, 1000)
+    );
+    let guest_hello = Hello::from_bytes(response.payload()).unwrap();
+    assert_eq!(guest_hello.version, PROTOCOL_VERSION);
+    assert_eq!(h.manager.connection_count(), 1);
+
+    // Runner to service.
+    let request = b"GET / HTTP/1.1\r\n\r\n";
+    h.runner_sends(VSOCK_OP_RW, 1000, 0, request);
+    assert_eq!(service.received(), request);
+
+    // The agent does not echo the runner's data back.
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent().len(), 1);
+
+    // Service to runner.
+    service.send(b"HTTP/1.1 200 OK\r\n\r\n");
+    h.manager.poll_vsock_connections().unwrap();
+    let sent = h.sent();
+    assert_eq!(sent.len(), 2);
+    let rw = &sent[1];
+    assert_eq!(rw.hdr().op, VSOCK_OP_RW);
+    assert_eq!((rw.hdr().src_port, rw.hdr().dst_port), (SERVICE_PORT, 1000));
+    assert_eq!(rw.payload(), b"HTTP/1.1 200 OK\r\n\r\n");
+    // The forwarded request is reflected in the guest's credit.
+    assert_eq!(rw.hdr().fwd_cnt, request.len() as u32);
+}
+
+#[test]
+fn stall_is_detected_after_threshold_idle_iterations() {
+    let mut h = Harness::new(AgentConfig {
+        stall_threshold: Some(3),
+        ..AgentConfig::default()
+    });
+    let _service = h.open(1000, SERVICE_PORT);
+    // Opening the connection was progress.
+    assert!(!h.manager.check_stall());
+
+    // Mutual silence: neither the runner nor the service sends anything.
+    for _ in 0..2 {
+        h.manager.poll_vsock_connections().unwrap();
+        h.manager.poll_cmio().unwrap();
+        assert!(!h.manager.check_stall());
+    }
+    h.manager.poll_vsock_connections().unwrap();
+    h.manager.poll_cmio().unwrap();
+    assert!(h.manager.check_stall());
+
+    // The count restarts once the stall has been reported.
+    assert!(!h.manager.check_stall());
+}
+
+#[test]
+fn traffic_resets_the_stall_count() {
+    let mut h = Harness::new(AgentConfig {
+        stall_threshold: Some(2),
+        ..AgentConfig::default()
+    });
+    let service = h.open(1000, SERVICE_PORT);
+    h.manager.check_stall();
+
+    assert!(!h.manager.check_stall());
+    service.send(b"data");
+    h.manager.poll_vsock_connections().unwrap();
+    assert!(!h.manager.check_stall());
+    assert!(!h.manager.check_stall());
+    assert!(h.manager.check_stall());
+}
+
+#[test]
+fn no_stall_without_connections() {
+    let mut h = Harness::new(AgentConfig {
+        stall_threshold: Some(1),
+        ..AgentConfig::default()
+    });
+    for _ in 0..3 {
+        h.manager.poll_cmio().unwrap();
+        assert!(!h.manager.check_stall());
+    }
+}
+
+#[test]
+fn stall_detection_is_off_by_default() {
+    let mut h = Harness::new(AgentConfig::default());
+    h.open(1000, SERVICE_PORT);
+    for _ in 0..100 {
+        h.manager.poll_cmio().unwrap();
+        assert!(!h.manager.check_stall());
+    }
+}
+
+#[test]
+fn stall_credit_update_advertises_guest_credit() {
+    let mut h = Harness::new(AgentConfig::default());
+    let _service = h.open(1000, SERVICE_PORT);
+
+    h.manager.send_credit_updates();
+    let update = h.sent().pop().unwrap();
+    assert_eq!(update.hdr().op, VSOCK_OP_CREDIT_UPDATE);
+    assert_eq!(update.hdr().type_, VSOCK_TYPE_STREAM);
+    assert_eq!(update.hdr().dst_port, 1000);
+    assert_eq!(update.hdr().buf_alloc, RW_BUF_SIZE as u32);
+}
+
+#[test]
+fn rcv_shutdown_discards_data_but_still_sees_eof() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+    h.runner_sends(VSOCK_OP_SHUTDOWN, 1000, VSOCK_SHUTDOWN_RCV, &[]);
+    assert_eq!(h.manager.connection_count(), 1);
+
+    service.send(b"unwanted");
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE]);
+
+    service.close();
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_SHUTDOWN]);
+    assert_eq!(h.manager.connection_count(), 0);
+}
+
+#[test]
+fn send_shutdown_half_closes_the_local_stream() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+    h.runner_sends(VSOCK_OP_SHUTDOWN, 1000, VSOCK_SHUTDOWN_SEND, &[]);
+    assert_eq!(service.shutdowns(), [Shutdown::Write]);
+
+    // Replies are still forwarded.
+    service.send(b"reply");
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent().last().unwrap().payload(), b"reply");
+
+    h.runner_sends(VSOCK_OP_SHUTDOWN, 1000, VSOCK_SHUTDOWN_RCV, &[]);
+    assert_eq!(h.manager.connection_count(), 0);
+    assert_eq!(service.shutdowns(), [Shutdown::Write, Shutdown::Both]);
+}
+
+#[test]
+fn packet_split_across_polls_is_reassembled() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+
+    let first = Packet::new(runner_hdr(VSOCK_OP_RW, 1000, 5, 0), b"hello".to_vec()).to_bytes();
+    let second = Packet::new(runner_hdr(VSOCK_OP_RW, 1000, 5, 0), b"world".to_vec()).to_bytes();
+    let split = HDR_SIZE + 2;
+
+    h.push_from_runner(first[..split].to_vec());
+    h.manager.poll_cmio().unwrap();
+    assert!(service.received().is_empty());
+    assert_eq!(h.manager.rx_pending, &first[..split]);
+
+    // The rest of the first packet arrives with the start of the second.
+    let mut rest = first[split..].to_vec();
+    rest.extend_from_slice(&second[..10]);
+    h.push_from_runner(rest);
+    h.manager.poll_cmio().unwrap();
+    assert_eq!(service.received(), b"hello");
+    assert_eq!(h.manager.rx_pending, &second[..10]);
+
+    h.push_from_runner(second[10..].to_vec());
+    h.manager.poll_cmio().unwrap();
+    assert_eq!(service.received(), b"helloworld");
+    assert!(h.manager.rx_pending.is_empty());
+}
+
+#[test]
+fn local_stream_data_is_forwarded_to_cmio() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+    service.send(b"preloaded bytes");
+
+    h.manager.poll_vsock_connections().unwrap();
+    let rw = h.sent().pop().unwrap();
+    assert_eq!(rw.hdr().op, VSOCK_OP_RW);
+    assert_eq!(rw.hdr().dst_port, 1000);
+    assert_eq!(rw.payload(), b"preloaded bytes");
+
+    // Nothing more to read: nothing more is sent.
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_RW]);
+}
+
+#[test]
+fn burst_cap_services_connections_round_robin() {
+    let mut h = Harness::new(AgentConfig {
+        max_packets_per_poll: 1,
+        ..AgentConfig::default()
+    });
+    let services: Vec<MemStream> = (1..=3).map(|i| h.open(1000 + i, 8080 + i)).collect();
+    let first_rw = h.sent().len();
+
+    let mut order = Vec::new();
+    for _ in 0..2 {
+        for service in &services {
+            service.send(b"data");
+        }
+        for _ in 0..3 {
+            h.manager.poll_vsock_connections().unwrap();
+            order.push(h.sent().last().unwrap().hdr().dst_port);
+        }
+    }
+    assert_eq!(order, [1001, 1002, 1003, 1001, 1002, 1003]);
+    assert_eq!(h.sent().len(), first_rw + 6);
+}
+
+#[test]
+fn idle_stream_is_reset_after_read_timeout() {
+    let mut h = Harness::new(AgentConfig {
+        read_timeout: Some(Duration::ZERO),
+        ..AgentConfig::default()
+    });
+    let idle = h.open(1000, 8081);
+    let busy = h.open(1001, 8082);
+    busy.send(b"data");
+
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(
+        h.manager.connection_keys(),
+        [ConnectionKey {
+            cid: RUNNER_CID,
+            port: 1001
+        }]
+    );
+    let rst = h.sent().pop().unwrap();
+    assert_eq!((rst.hdr().op, rst.hdr().dst_port), (VSOCK_OP_RST, 1000));
+    assert_eq!(idle.shutdowns(), [Shutdown::Both]);
+}
+
+#[test]
+fn connection_count_and_keys_track_open_connections() {
+    let mut h = Harness::new(AgentConfig::default());
+    assert_eq!(h.manager.connection_count(), 0);
+    assert!(h.manager.connection_keys().is_empty());
+
+    for port in [1003, 1001, 1002] {
+        h.open(port, 8080 + port);
+    }
+    assert_eq!(h.manager.connection_count(), 3);
+    let ports: Vec<u32> = h
+        .manager
+        .connection_keys()
+        .iter()
+        .map(|key| key.port)
+        .collect();
+    assert_eq!(ports, [1001, 1002, 1003]);
+    assert!(h
+        .manager
+        .connection_keys()
+        .iter()
+        .all(|key| key.cid == RUNNER_CID));
+
+    h.runner_sends(VSOCK_OP_RST, 1002, 0, &[]);
+    assert_eq!(h.manager.connection_count(), 2);
+}
+
+#[test]
+fn mock_clock_drives_read_timeout_without_sleeping() {
+    let clock = Arc::new(MockClock::new());
+    let mut h = Harness::new(AgentConfig {
+        read_timeout: Some(Duration::from_secs(1)),
+        clock: clock.clone(),
+        ..AgentConfig::default()
+    });
+    h.open(1000, SERVICE_PORT);
+
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.manager.connection_count(), 1);
+
+    clock.advance(Duration::from_secs(2));
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.manager.connection_count(), 0);
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_RST]);
+}
+
+#[test]
+fn rst_after_shutdown_is_a_no_op() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+
+    h.runner_sends(VSOCK_OP_SHUTDOWN, 1000, 0, &[]);
+    assert_eq!(h.manager.connection_count(), 0);
+    assert_eq!(service.shutdowns(), [Shutdown::Both]);
+
+    h.runner_sends(VSOCK_OP_RST, 1000, 0, &[]);
+    assert_eq!(service.shutdowns(), [Shutdown::Both]);
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE]);
+}
+
+#[test]
+fn duplicate_request_is_answered_without_reconnecting() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = MemStream::listen(SERVICE_PORT);
+    h.runner_sends(VSOCK_OP_REQUEST, 1000, 0, &[]);
+    h.runner_sends(VSOCK_OP_REQUEST, 1000, 0, &[]);
+
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_RESPONSE]);
+    assert_eq!(service.connects(), 1);
+    assert_eq!(h.manager.connection_count(), 1);
+    assert!(service.shutdowns().is_empty());
+}
+
+#[test]
+fn full_credit_window_pauses_reading_until_the_runner_forwards() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = MemStream::listen(SERVICE_PORT);
+    let with_credit = |op, fwd_cnt| VirtioVsockHdr {
+        buf_alloc: 8,
+        fwd_cnt,
+        ..runner_hdr(op, 1000, 0, 0)
+    };
+    h.push_from_runner(Packet::new(with_credit(VSOCK_OP_REQUEST, 0), vec![]).to_bytes());
+    h.manager.poll_cmio().unwrap();
+    service.send(b"0123456789abcdef");
+
+    // The first pass fills the runner's 8-byte window.
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent().last().unwrap().payload(), b"01234567");
+    assert_eq!(service.unread(), 8);
+
+    // With the window full the local stream is not read.
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent().len(), 2);
+    assert_eq!(service.unread(), 8);
+
+    // A header advancing fwd_cnt reopens the window.
+    h.push_from_runner(Packet::new(with_credit(VSOCK_OP_CREDIT_UPDATE, 8), vec![]).to_bytes());
+    h.manager.poll_cmio().unwrap();
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent().last().unwrap().payload(), b"89abcdef");
+    assert_eq!(service.unread(), 0);
+}
+
+fn lingering(clock: &Arc<MockClock>) -> Harness {
+    Harness::new(AgentConfig {
+        rw_linger: Some(Duration::from_secs(1)),
+        clock: clock.clone(),
+        ..AgentConfig::default()
+    })
+}
+
+#[test]
+fn writes_within_the_linger_are_sent_together() {
+    let clock = Arc::new(MockClock::new());
+    let mut h = lingering(&clock);
+    let service = h.open(1000, SERVICE_PORT);
+
+    for chunk in [&b"a"[..], b"b", b"c"] {
+        service.send(chunk);
+        h.manager.poll_vsock_connections().unwrap();
+        clock.advance(Duration::from_millis(300));
+    }
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE]);
+
+    clock.advance(Duration::from_millis(100));
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_RW]);
+    assert_eq!(h.sent()[1].payload(), b"abc");
+}
+
+#[test]
+fn lone_write_is_flushed_when_the_linger_expires() {
+    let clock = Arc::new(MockClock::new());
+    let mut h = lingering(&clock);
+    let service = h.open(1000, SERVICE_PORT);
+    assert_eq!(h.manager.idle_sleep(), LOOP_SLEEP_DURATION);
+
+    service.send(b"x");
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE]);
+
+    // The loop wakes up at the linger deadline rather than after a full
+    // idle sleep.
+    let sleep = h.manager.idle_sleep();
+    assert_eq!(sleep, Duration::from_secs(1));
+    clock.sleep(sleep);
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent()[1].payload(), b"x");
+    assert_eq!(h.manager.idle_sleep(), LOOP_SLEEP_DURATION);
+}
+
+#[test]
+fn request_with_another_protocol_version_is_reset() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = MemStream::listen(SERVICE_PORT);
+    let hello = Hello {
+        version: PROTOCOL_VERSION + 1,
+        features: 0,
+    };
+    h.runner_sends(VSOCK_OP_REQUEST, 1000, 0, &hello.to_bytes());
+
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RST]);
+    assert_eq!(h.manager.connection_count(), 0);
+    assert_eq!(service.connects(), 0);
+}
+
+#[test]
+fn invalid_header_discards_buffered_bytes() {
+    let mut h = Harness::new(AgentConfig::default());
+    let mut garbage = runner_hdr(VSOCK_OP_RW, 1000, 4, 0).to_bytes();
+    garbage[22..24].copy_from_slice(&9999u16.to_le_bytes());
+    h.push_from_runner(garbage);
+    h.manager.poll_cmio().unwrap();
+    assert!(h.manager.rx_pending.is_empty());
+
+    // Later packets are parsed from a clean buffer.
+    h.open(1000, SERVICE_PORT);
+    assert_eq!(h.manager.connection_count(), 1);
+}
+
+#[test]
+fn reply_to_a_data_yield_is_reassembled() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+    let rw = Packet::new(runner_hdr(VSOCK_OP_RW, 1000, 10, 0), b"helloworld".to_vec()).to_bytes();
+    let split = HDR_SIZE + 5;
+
+    h.push_from_runner(rw[..split].to_vec());
+    h.manager.poll_cmio().unwrap();
+    assert!(service.received().is_empty());
+
+    // The rest of the packet arrives as the runner's answer to the guest's
+    // RW send rather than to an empty poll.
+    h.driver
+        .lock()
+        .unwrap()
+        .push_data_reply(rw[split..].to_vec());
+    service.send(b"reply");
+    h.manager.poll_vsock_connections().unwrap();
+    h.manager.poll_cmio().unwrap();
+    assert_eq!(service.received(), b"helloworld");
+    assert!(h.manager.rx_pending.is_empty());
+}
+
+fn sorted_snapshot(manager: &ConnectionManager<MemStream>) -> SessionSnapshot {
+    let mut snapshot = manager.export_session();
+    snapshot
+        .connections
+        .sort_by_key(|conn| ConnectionKey::from(&conn.request_hdr));
+    snapshot
+}
+
+#[test]
+fn exported_session_imports_into_a_fresh_manager() {
+    let mut source = Harness::new(AgentConfig::default());
+    let first = source.open(1000, 8081);
+    source.open(1001, 8082);
+    source.runner_sends(VSOCK_OP_RW, 1000, 0, b"request");
+    first.send(b"response");
+    source.manager.poll_vsock_connections().unwrap();
+    let snapshot = sorted_snapshot(&source.manager);
+    assert_eq!(snapshot.connections.len(), 2);
+    assert_eq!(snapshot.connections[0].credit.fwd_cnt(), 7);
+
+    let mut destination = Harness::new(AgentConfig::default());
+    destination.manager.import_session(snapshot.clone());
+    assert_eq!(
+        destination.manager.connection_keys(),
+        source.manager.connection_keys()
+    );
+    assert_eq!(sorted_snapshot(&destination.manager), snapshot);
+    assert!(destination.sent().is_empty());
+    assert_eq!(first.connects(), 2);
+}
+
+#[test]
+fn import_resets_connections_whose_service_is_gone() {
+    let mut source = Harness::new(AgentConfig::default());
+    source.open(1000, 8081);
+    let mut snapshot = source.manager.export_session();
+    snapshot.connections[0].request_hdr.dst_port = 9999;
+
+    let mut destination = Harness::new(AgentConfig::default());
+    destination.manager.import_session(snapshot);
+    assert_eq!(destination.manager.connection_count(), 0);
+    let rst = destination.sent().pop().unwrap();
+    assert_eq!(rst.hdr().op, VSOCK_OP_RST);
+    assert_eq!((rst.hdr().src_port, rst.hdr().dst_port), (9999, 1000));
+}
+
+#[test]
+fn crossed_shutdowns_complete_the_close() {
+    let mut h = Harness::new(AgentConfig::default());
+    let service = h.open(1000, SERVICE_PORT);
+    let key = ConnectionKey {
+        cid: RUNNER_CID,
+        port: 1000,
+    };
+
+    // The service closes, so the guest sends SHUTDOWN...
+    service.close();
+    h.manager.poll_vsock_connections().unwrap();
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_SHUTDOWN]);
+    assert_eq!(h.manager.connection_count(), 0);
+    assert!(h.manager.closing.contains_key(&key));
+
+    // ...while the runner's own SHUTDOWN is already on its way.
+    h.runner_sends(
+        VSOCK_OP_SHUTDOWN,
+        1000,
+        VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND,
+        &[],
+    );
+    assert_eq!(h.sent_ops(), [VSOCK_OP_RESPONSE, VSOCK_OP_SHUTDOWN]);
+    assert!(h.manager.closing.is_empty());
+    assert_eq!(service.shutdowns(), [Shutdown::Both]);
+}
@@ -1,62 +1,190 @@
 use cmio::CmioIoDriver;
 use log::{error, info};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 use vsock::{VsockAddr, VsockListener, VsockStream, VMADDR_CID_ANY};
 const BUFFER_SIZE: usize = 4096;
+/// CMIO domain the host agent's handshake has always been sent on.
+///
+/// This differs from the guest agent's default, `cmio::DOMAIN_VSOCK`. The
+/// two only interoperate over a transport that does not route by domain,
+/// such as the mock CMIO driver the host agent links against; set
+/// [`AgentConfig::queue_id`] to `cmio::DOMAIN_VSOCK` for one that does.
+pub const DEFAULT_QUEUE_ID: u16 = 1;
 use vsock_protocol::{
-    Packet, VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_TYPE_STREAM,
+    hex_dump, Framing, Packet, RawFraming, VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE,
 };
 
+/// Tunables for the host agent.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    /// Number of consecutive handshake replies that fail to parse as a packet
+    /// before the handshake is abandoned. Replies that parse but are not
+    /// `VSOCK_OP_RESPONSE` are retried indefinitely.
+    pub max_handshake_parse_failures: u32,
+    /// Encoding applied to packets on the CMIO channel. The guest agent must
+    /// use the same framing. Defaults to [`RawFraming`].
+    pub framing: Arc<dyn Framing>,
+    /// CMIO domain the handshake is sent on. Defaults to
+    /// [`DEFAULT_QUEUE_ID`].
+    pub queue_id: u16,
+    /// How long to wait before resending the connection request when the
+    /// guest has not answered it yet.
+    pub handshake_retry_interval: Duration,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            max_handshake_parse_failures: 5,
+            framing: Arc::new(RawFraming),
+            queue_id: DEFAULT_QUEUE_ID,
+            handshake_retry_interval: Duration::from_secs(5),
+        }
+    }
+}
+
 /// Runs the main logic of the host agent.
 pub fn run_agent(
     cmio_driver: Arc<Mutex<CmioIoDriver>>,
     host_cid: u32,
     host_port: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    run_agent_with_config(cmio_driver, host_cid, host_port, AgentConfig::default())
+}
+
+/// Runs the main logic of the host agent with the given configuration.
+pub fn run_agent_with_config(
+    cmio_driver: Arc<Mutex<CmioIoDriver>>,
+    host_cid: u32,
+    host_port: u32,
+    config: AgentConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let agent = connect_with_config(cmio_driver, host_cid, host_port, config)?;
+    handle_host_stream(agent.into_inner())
+}
+
+/// Performs the CMIO handshake and accepts the guest's vsock connection,
+/// returning a [`HostAgent`] over it.
+pub fn connect_with_config(
+    cmio_driver: Arc<Mutex<CmioIoDriver>>,
+    host_cid: u32,
+    host_port: u32,
+    config: AgentConfig,
+) -> Result<HostAgent<VsockStream>, Box<dyn std::error::Error>> {
     let listener = VsockListener::bind(&VsockAddr::new(VMADDR_CID_ANY, host_port))?;
     info!(target: "host", "HOST AGENT STARTED.");
     info!(target: "host", "LISTENING ON THE PORT: {} CID: {}", host_port, host_cid);
 
-    let request_hdr = VirtioVsockHdr {
-        src_cid: host_cid,
-        dst_cid: host_cid,
-        src_port: host_port,
-        dst_port: host_port,
-        len: 0,
-        type_: VSOCK_TYPE_STREAM,
-        op: VSOCK_OP_REQUEST,
-        flags: 0,
-        buf_alloc: 0,
-        fwd_cnt: 0,
-    };
+    handshake(&cmio_driver, host_cid, host_port, &config)?;
+
+    let (stream, _addr) = listener.accept()?;
+    Ok(HostAgent::new(stream))
+}
+
+/// Sends a vsock connection request for `host_cid:host_port` over CMIO
+/// until the guest agent answers it with `VSOCK_OP_RESPONSE`.
+///
+/// The guest agent answers by connecting to that address, so the caller
+/// must be ready to accept the connection before calling this.
+pub fn handshake(
+    cmio_driver: &Mutex<CmioIoDriver>,
+    host_cid: u32,
+    host_port: u32,
+    config: &AgentConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request_hdr = VirtioVsockHdr::builder()
+        .src(host_cid, host_port)
+        .dst(host_cid, host_port)
+        .type_stream()
+        .op(VSOCK_OP_REQUEST)
+        .build();
     let request_packet = Packet::new(request_hdr, vec![]);
-    let request_bytes = request_packet.to_bytes();
+    let request_bytes = config
+        .framing
+        .encode(&request_packet.to_bytes())
+        .into_owned();
 
+    let mut parse_failures = 0;
     loop {
         let response_bytes = {
             let mut driver = cmio_driver.lock().unwrap();
-            driver.send_cmio(&request_bytes, 1)?
+            driver.send_cmio(&request_bytes, config.queue_id)?
         };
 
         if !response_bytes.is_empty() {
-            if let Ok(packet) = Packet::from_bytes(&response_bytes) {
-                if packet.hdr().op == VSOCK_OP_RESPONSE {
-                    info!(target: "host", "HOST: QUERY OP_RESPONSE SUCCESSFUL. CONTINUING WITH VSock CONNECTION.");
-                    break;
+            let parsed = config
+                .framing
+                .decode(&response_bytes)
+                .and_then(|bytes| Packet::from_bytes(&bytes));
+            match parsed {
+                Ok(packet) => {
+                    parse_failures = 0;
+                    if packet.hdr().op == VSOCK_OP_RESPONSE {
+                        info!(target: "host", "HOST: QUERY OP_RESPONSE SUCCESSFUL. CONTINUING WITH VSock CONNECTION.");
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    parse_failures += 1;
+                    error!(
+                        target: "host",
+                        "HOST: FAILED TO PARSE HANDSHAKE REPLY ({}/{}): {}\n{}",
+                        parse_failures,
+                        config.max_handshake_parse_failures,
+                        e,
+                        hex_dump(&response_bytes)
+                    );
+                    if parse_failures >= config.max_handshake_parse_failures {
+                        return Err(format!(
+                            "handshake failed: {} consecutive replies were not vsock packets",
+                            parse_failures
+                        )
+                        .into());
+                    }
                 }
             }
         }
 
-        info!(target: "host", "HOST: QUERY OP_RESPONSE FAILED, RETRYING IN 5 SECONDS...");
-        thread::sleep(Duration::from_secs(5));
+        info!(
+            target: "host",
+            "HOST: QUERY OP_RESPONSE FAILED, RETRYING IN {:?}...",
+            config.handshake_retry_interval
+        );
+        thread::sleep(config.handshake_retry_interval);
     }
+}
 
-    let (stream, _addr) = listener.accept()?;
-    handle_host_stream(stream)
+/// An established connection to the guest that carries raw vsock packets,
+/// for callers implementing their own protocol on top.
+pub struct HostAgent<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> HostAgent<S> {
+    /// Wraps an established stream to the guest.
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// Serializes and sends a packet to the guest.
+    pub fn send_packet(&mut self, packet: &Packet) -> io::Result<()> {
+        packet.write_to(&mut self.stream)?;
+        self.stream.flush()
+    }
+
+    /// Blocks until a full packet has been read from the guest.
+    pub fn recv_packet(&mut self) -> io::Result<Packet> {
+        Packet::from_read(&mut self.stream)
+    }
+
+    /// Consumes the agent and returns the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
 }
 
 /// Handles a raw data stream from the guest agent, echoing back any data it receives.
@@ -103,3 +231,86 @@ fn handle_host_stream(mut stream: VsockStream) -> Result<(), Box<dyn std::error:
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use vsock_protocol::{VSOCK_OP_RST, VSOCK_OP_RW};
+
+    fn quick_config(max_handshake_parse_failures: u32) -> AgentConfig {
+        AgentConfig {
+            max_handshake_parse_failures,
+            handshake_retry_interval: Duration::ZERO,
+            ..AgentConfig::default()
+        }
+    }
+
+    #[test]
+    fn handshake_gives_up_on_garbage_replies() {
+        let driver = Mutex::new(CmioIoDriver::new().unwrap());
+        for _ in 0..3 {
+            driver.lock().unwrap().push_data_reply(vec![0xde; 10]);
+        }
+
+        let err = handshake(&driver, 2, 8080, &quick_config(3)).unwrap_err();
+        assert!(err.to_string().contains("3 consecutive replies"), "{}", err);
+        let sent = driver.lock().unwrap().sent().to_vec();
+        assert_eq!(sent.len(), 3);
+        let request = Packet::from_bytes(&sent[0]).unwrap();
+        assert_eq!(request.hdr().op, VSOCK_OP_REQUEST);
+    }
+
+    #[test]
+    fn handshake_parse_failures_must_be_consecutive() {
+        let driver = Mutex::new(CmioIoDriver::new().unwrap());
+        let response = VirtioVsockHdr::builder()
+            .type_stream()
+            .op(VSOCK_OP_RESPONSE)
+            .build();
+        {
+            let mut driver = driver.lock().unwrap();
+            driver.push_data_reply(vec![0xde; 10]);
+            // A reply that parses but is not a RESPONSE restarts the count.
+            driver.push_data_reply(
+                VirtioVsockHdr::builder()
+                    .type_stream()
+                    .op(VSOCK_OP_RST)
+                    .build()
+                    .to_bytes(),
+            );
+            driver.push_data_reply(vec![0xde; 10]);
+            driver.push_data_reply(response.to_bytes());
+        }
+
+        handshake(&driver, 2, 8080, &quick_config(2)).unwrap();
+        assert_eq!(driver.lock().unwrap().sent().len(), 4);
+    }
+
+    fn rw_packet(payload: &[u8]) -> Packet {
+        let hdr = VirtioVsockHdr::builder()
+            .src(2, 8080)
+            .dst(3, 1234)
+            .type_stream()
+            .op(VSOCK_OP_RW)
+            .len(payload.len() as u32)
+            .build();
+        Packet::new(hdr, payload.to_vec())
+    }
+
+    #[test]
+    fn host_agent_sends_serialized_packets() {
+        let packet = rw_packet(b"ping");
+        let mut agent = HostAgent::new(Cursor::new(Vec::new()));
+        agent.send_packet(&packet).unwrap();
+        assert_eq!(agent.into_inner().into_inner(), packet.to_bytes());
+    }
+
+    #[test]
+    fn host_agent_receives_a_reply() {
+        let reply = rw_packet(b"pong");
+        let mut agent = HostAgent::new(Cursor::new(reply.to_bytes()));
+        assert_eq!(agent.recv_packet().unwrap(), reply);
+        assert!(agent.recv_packet().is_err());
+    }
+}
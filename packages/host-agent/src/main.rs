@@ -6,6 +6,12 @@ use log::{error, info, LevelFilter};
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::Mutex;
+use vsock::VMADDR_CID_HOST;
+
+/// The CID the host agent identifies itself with in the handshake.
+const HOST_CID: u32 = VMADDR_CID_HOST;
+/// The port the host agent listens on and handshakes with.
+const HOST_PORT: u32 = 8080;
 
 fn main() {
     let mut builder = Builder::new();
@@ -29,7 +35,7 @@ fn main() {
 
     info!("Starting host agent");
     let driver = Arc::new(Mutex::new(CmioIoDriver::new().unwrap()));
-    if let Err(e) = run_agent(driver) {
+    if let Err(e) = run_agent(driver, HOST_CID, HOST_PORT) {
         error!("Host agent exited with error: {}", e);
     }
 }
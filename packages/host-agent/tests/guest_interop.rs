@@ -0,0 +1,208 @@
+//! Runs the host agent's handshake against the real guest agent loop over one
+//! shared mock CMIO driver, with an in-memory pipe standing in for the vsock
+//! connection the guest agent opens back to the host.
+
+use cmio::CmioIoDriver;
+use guest_agent::{run_agent_with_stream, AgentConfig as GuestConfig, Clock, LocalStream};
+use host_agent::{handshake, AgentConfig, HostAgent};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use vsock_protocol::{Packet, VirtioVsockHdr, VSOCK_OP_RW};
+
+const HOST_CID: u32 = 2;
+const HOST_PORT: u32 = 8080;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct PipeState {
+    to_guest: VecDeque<u8>,
+    to_host: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Listening pipes by `(cid, port)`.
+type Listeners = Mutex<HashMap<(u32, u32), Arc<Mutex<PipeState>>>>;
+
+/// Addresses the guest agent can connect to, standing in for vsock listeners.
+fn listeners() -> &'static Listeners {
+    static LISTENERS: OnceLock<Listeners> = OnceLock::new();
+    LISTENERS.get_or_init(Default::default)
+}
+
+/// Makes `cid:port` reachable by the guest agent and returns the host's end.
+fn listen(cid: u32, port: u32) -> HostEnd {
+    let state = Arc::new(Mutex::new(PipeState::default()));
+    listeners()
+        .lock()
+        .unwrap()
+        .insert((cid, port), state.clone());
+    HostEnd(state)
+}
+
+/// The guest agent's end of the pipe; nonblocking, as [`LocalStream`] requires.
+struct GuestEnd(Arc<Mutex<PipeState>>);
+
+impl Read for GuestEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        if state.to_guest.is_empty() {
+            return if state.closed {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+        let n = buf.len().min(state.to_guest.len());
+        for (dst, src) in buf.iter_mut().zip(state.to_guest.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for GuestEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().to_host.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStream for GuestEnd {
+    fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let state = listeners().lock().unwrap().get(&(cid, port)).cloned();
+        state
+            .map(GuestEnd)
+            .ok_or_else(|| io::ErrorKind::ConnectionRefused.into())
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().closed = true;
+        Ok(())
+    }
+}
+
+/// The host's end of the pipe; reads block until data arrives, or fail after
+/// [`TIMEOUT`].
+struct HostEnd(Arc<Mutex<PipeState>>);
+
+impl Read for HostEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            {
+                let mut state = self.0.lock().unwrap();
+                if !state.to_host.is_empty() {
+                    let n = buf.len().min(state.to_host.len());
+                    for (dst, src) in buf.iter_mut().zip(state.to_host.drain(..n)) {
+                        *dst = src;
+                    }
+                    return Ok(n);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Write for HostEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().to_guest.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Real time, but the guest loop's idle sleep is cut to a millisecond.
+#[derive(Debug)]
+struct FastClock;
+
+impl Clock for FastClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration.min(Duration::from_millis(1)));
+    }
+}
+
+fn wait_until(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn host_handshake_and_packets_through_the_guest_agent() {
+    let driver = Arc::new(Mutex::new(CmioIoDriver::new().unwrap()));
+    let host_end = listen(HOST_CID, HOST_PORT);
+
+    let guest_driver = driver.clone();
+    thread::spawn(move || {
+        let config = GuestConfig {
+            clock: Arc::new(FastClock),
+            ..GuestConfig::default()
+        };
+        // The loop only returns on error, which the host side then times
+        // out on.
+        let _ = run_agent_with_stream::<GuestEnd>(guest_driver, config);
+    });
+
+    let config = AgentConfig {
+        handshake_retry_interval: Duration::from_millis(1),
+        ..AgentConfig::default()
+    };
+    handshake(&driver, HOST_CID, HOST_PORT, &config).unwrap();
+    let mut host = HostAgent::new(host_end);
+
+    // Host to runner: the guest agent reads the host's packet from its local
+    // stream and forwards it over CMIO as the payload of an RW packet.
+    let ping = Packet::new(
+        VirtioVsockHdr::builder()
+            .type_stream()
+            .op(VSOCK_OP_RW)
+            .len(4)
+            .build(),
+        b"ping".to_vec(),
+    );
+    host.send_packet(&ping).unwrap();
+    wait_until(|| {
+        driver.lock().unwrap().sent().iter().any(|bytes| {
+            Packet::from_bytes(bytes).is_ok_and(|packet| {
+                packet.hdr().op == VSOCK_OP_RW && packet.payload() == ping.to_bytes()
+            })
+        })
+    });
+
+    // Runner to host: an RW packet on CMIO for the connection is written to
+    // the host's stream.
+    let pong = Packet::new(*ping.hdr(), b"pong".to_vec());
+    let rw_hdr = VirtioVsockHdr::builder()
+        .src(HOST_CID, HOST_PORT)
+        .dst(HOST_CID, HOST_PORT)
+        .len(pong.to_bytes().len() as u32)
+        .type_stream()
+        .op(VSOCK_OP_RW)
+        .build();
+    driver
+        .lock()
+        .unwrap()
+        .push_response(Packet::new(rw_hdr, pong.to_bytes()).to_bytes());
+    assert_eq!(host.recv_packet().unwrap(), pong);
+}
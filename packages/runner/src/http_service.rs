@@ -1,28 +1,73 @@
+use crate::session::CmioRecorder;
 use crate::utils::{
     receive_packet, run_machine_until_yield, send_empty_response, send_packet, vsock_connect,
 };
 use cartesi_machine::machine::Machine;
 use log::info;
 use std::error::Error;
-use vsock_protocol::{VSOCK_OP_RW, VSOCK_OP_SHUTDOWN};
+use std::fmt;
+use vsock_protocol::{op_name, VSOCK_OP_RST, VSOCK_OP_RW, VSOCK_OP_SHUTDOWN};
+
+/// Why a request failed after it was sent to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFailure {
+    /// The guest reset the connection.
+    Reset,
+    /// The guest shut the connection down before responding.
+    Shutdown,
+}
+
+impl fmt::Display for RequestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestFailure::Reset => write!(f, "connection reset by guest"),
+            RequestFailure::Shutdown => write!(f, "connection shut down by guest"),
+        }
+    }
+}
+
+impl Error for RequestFailure {}
+
+impl RequestFailure {
+    /// Returns how a packet with `op` from the guest closes the connection,
+    /// or `None` if it does not.
+    fn from_op(op: u16) -> Option<Self> {
+        match op {
+            VSOCK_OP_RST => Some(RequestFailure::Reset),
+            VSOCK_OP_SHUTDOWN => Some(RequestFailure::Shutdown),
+            _ => None,
+        }
+    }
+}
 
 /// A simple HTTP service that communicates over a vsock stream.
 pub struct HttpService<'a> {
     machine: &'a mut Machine,
+    recorder: &'a mut CmioRecorder,
     guest_port: u32,
 }
 
 impl<'a> HttpService<'a> {
     /// Connects to the service on the guest machine.
-    pub fn connect(machine: &'a mut Machine, guest_port: u32) -> Result<Self, Box<dyn Error>> {
-        vsock_connect(machine, guest_port)?;
+    ///
+    /// Every CMIO response sent on the connection is handed to `recorder`.
+    pub fn connect(
+        machine: &'a mut Machine,
+        recorder: &'a mut CmioRecorder,
+        guest_port: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        vsock_connect(machine, recorder, guest_port)?;
         Ok(Self {
             machine,
+            recorder,
             guest_port,
         })
     }
 
     /// Performs a request by parsing the method and sending it to the guest.
+    ///
+    /// If the guest closes the connection before a response arrives, the
+    /// returned error is a [`RequestFailure`] describing how it was closed.
     pub fn request(&mut self, request: &str) -> Result<String, Box<dyn Error>> {
         let first_line = request.lines().next().ok_or("Empty request")?;
         let parts: Vec<&str> = first_line.split_whitespace().collect();
@@ -36,6 +81,7 @@ impl<'a> HttpService<'a> {
                 info!("Sending HTTP request to guest...");
                 send_packet(
                     self.machine,
+                    self.recorder,
                     self.guest_port,
                     VSOCK_OP_RW,
                     request.as_bytes(),
@@ -55,16 +101,25 @@ impl<'a> HttpService<'a> {
                                 break payload.to_vec();
                             } else {
                                 info!("Received empty RW packet, waiting...");
-                                send_empty_response(self.machine)?;
+                                send_empty_response(self.machine, self.recorder)?;
                                 run_machine_until_yield(self.machine)?;
                             }
-                        } else if packet.hdr().op == VSOCK_OP_SHUTDOWN {
-                            info!("Guest has shut down the connection.");
-                            return Err(format!("Shutdown").into());
+                        } else if let Some(failure) = RequestFailure::from_op(packet.hdr().op) {
+                            info!("Request failed: {}.", failure);
+                            return Err(failure.into());
+                        } else {
+                            // e.g. a credit update: nothing to act on, but the
+                            // guest must still be resumed to make progress.
+                            info!(
+                                "Ignoring {} from guest, waiting...",
+                                op_name(packet.hdr().op)
+                            );
+                            send_empty_response(self.machine, self.recorder)?;
+                            run_machine_until_yield(self.machine)?;
                         }
                     } else {
                         info!("No packet received, waiting...");
-                        send_empty_response(self.machine)?;
+                        send_empty_response(self.machine, self.recorder)?;
                         run_machine_until_yield(self.machine)?;
                     }
                 };
@@ -81,3 +136,34 @@ impl<'a> HttpService<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vsock_protocol::{VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_RESPONSE};
+
+    #[test]
+    fn reset_surfaces_as_reset() {
+        let err: Box<dyn Error> = RequestFailure::from_op(VSOCK_OP_RST).unwrap().into();
+        assert_eq!(
+            err.downcast_ref::<RequestFailure>(),
+            Some(&RequestFailure::Reset)
+        );
+        assert_eq!(err.to_string(), "connection reset by guest");
+    }
+
+    #[test]
+    fn shutdown_surfaces_as_shutdown() {
+        assert_eq!(
+            RequestFailure::from_op(VSOCK_OP_SHUTDOWN),
+            Some(RequestFailure::Shutdown)
+        );
+    }
+
+    #[test]
+    fn other_ops_do_not_fail_the_request() {
+        for op in [VSOCK_OP_RW, VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_RESPONSE] {
+            assert_eq!(RequestFailure::from_op(op), None, "{}", op_name(op));
+        }
+    }
+}
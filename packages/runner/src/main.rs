@@ -1,13 +1,17 @@
 use env_logger::Builder;
 use log::{info, LevelFilter};
+use std::env;
 use std::error::Error;
+use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
 use cartesi_machine::{config::runtime::RuntimeConfig, machine::Machine};
 mod http_service;
+mod session;
 mod utils;
 use http_service::HttpService;
+use session::{replay_cmio_session, CmioRecorder};
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -15,6 +19,10 @@ use std::time::Duration;
 const MACHINE_PATH: &str = "../../vc-cm-snapshot-release";
 /// The port the guest machine is listening on.
 const GUEST_PORT: u32 = 8080;
+/// If set, the CMIO responses sent to the machine are recorded to this file.
+const RECORD_ENV: &str = "VCR_CMIO_RECORD";
+/// If set, the CMIO session in this file is replayed instead of running normally.
+const REPLAY_ENV: &str = "VCR_CMIO_REPLAY";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -24,21 +32,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let mut machine = Machine::load(Path::new(MACHINE_PATH), &RuntimeConfig::default())?;
 
+    if let Ok(path) = env::var(REPLAY_ENV) {
+        info!("Replaying CMIO session from {}", path);
+        let mcycle = replay_cmio_session(&mut machine, File::open(path)?)?;
+        info!("Replay finished at cycle {}", mcycle);
+        return Ok(());
+    }
+
+    let mut recorder = match env::var(RECORD_ENV) {
+        Ok(path) => {
+            info!("Recording CMIO session to {}", path);
+            CmioRecorder::new(File::create(path)?)
+        }
+        Err(_) => CmioRecorder::disabled(),
+    };
+
     'health_check: loop {
         info!("Attempting to connect to HTTP service...");
-        match HttpService::connect(&mut machine, GUEST_PORT) {
+        match HttpService::connect(&mut machine, &mut recorder, GUEST_PORT) {
             Ok(mut service) => {
                 info!("Successfully connected to HTTP service.");
                 loop {
                     let get_request =
                         "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
                     info!("Performing GET request...");
-                    if service.request(get_request).is_ok() {
-                        info!("Transaction complete.");
-                        break 'health_check;
-                    } else {
-                        info!("Request failed. Will attempt to reconnect.");
-                        break;
+                    match service.request(get_request) {
+                        Ok(_) => {
+                            info!("Transaction complete.");
+                            break 'health_check;
+                        }
+                        Err(e) => {
+                            info!("Request failed: {}. Will attempt to reconnect.", e);
+                            break;
+                        }
                     }
                 }
             }
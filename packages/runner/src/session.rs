@@ -0,0 +1,164 @@
+use crate::utils::run_machine_until_yield;
+use cartesi_machine::machine::Machine;
+use cartesi_machine::types::cmio::CmioResponseReason;
+use log::{error, info};
+use std::error::Error;
+use std::io::{self, Read, Write};
+
+/// Records every CMIO response the runner sends to the machine.
+///
+/// Each response is written as a little-endian `u32` length followed by the
+/// response bytes. Since the machine is deterministic, replaying the recording
+/// with [`replay_cmio_session`] against the same snapshot reproduces the run.
+#[derive(Default)]
+pub struct CmioRecorder {
+    writer: Option<Box<dyn Write>>,
+}
+
+impl CmioRecorder {
+    /// Returns a recorder that records nothing.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Starts recording to `writer`.
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Some(Box::new(writer)),
+        }
+    }
+
+    /// Appends a response to the recording, if any.
+    ///
+    /// A failed write is logged and stops the recording; it never interrupts
+    /// the run itself.
+    pub fn record(&mut self, data: &[u8]) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = write_cmio_response(writer, data) {
+                error!("Failed to record CMIO response, recording stopped: {}", e);
+                self.writer = None;
+            }
+        }
+    }
+}
+
+fn write_cmio_response(mut writer: impl Write, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Reads the next recorded response, or `None` at the end of the recording.
+fn read_cmio_response(mut reader: impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut data = vec![0; u32::from_le_bytes(len_buf) as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Replays a session recorded with a [`CmioRecorder`] against a machine
+/// freshly loaded from the same snapshot.
+///
+/// Each recorded response is sent at the machine's next yield, exactly as the
+/// runner did. Returns the machine's `mcycle` at the yield that follows the
+/// last response.
+pub fn replay_cmio_session(
+    machine: &mut Machine,
+    mut reader: impl Read,
+) -> Result<u64, Box<dyn Error>> {
+    let mut responses = 0;
+    while let Some(data) = read_cmio_response(&mut reader)? {
+        run_machine_until_yield(machine)?;
+        machine.send_cmio_response(CmioResponseReason::Advance, &data)?;
+        responses += 1;
+    }
+
+    run_machine_until_yield(machine)?;
+    let mcycle = machine.mcycle()?;
+    info!("Replayed {} CMIO responses, cycle {}", responses, mcycle);
+    Ok(mcycle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer whose contents stay readable after it is moved into a recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn recorded_responses_read_back_in_order() {
+        let buf = SharedBuf::default();
+        let mut recorder = CmioRecorder::new(buf.clone());
+        recorder.record(b"first");
+        recorder.record(&[]);
+        recorder.record(b"third");
+
+        let recording = buf.0.borrow().clone();
+        let mut reader = recording.as_slice();
+        assert_eq!(
+            read_cmio_response(&mut reader).unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(read_cmio_response(&mut reader).unwrap(), Some(Vec::new()));
+        assert_eq!(
+            read_cmio_response(&mut reader).unwrap(),
+            Some(b"third".to_vec())
+        );
+        assert_eq!(read_cmio_response(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_recording_is_an_error() {
+        let mut recording = Vec::new();
+        write_cmio_response(&mut recording, b"response").unwrap();
+        recording.pop();
+        assert!(read_cmio_response(recording.as_slice()).is_err());
+    }
+
+    #[test]
+    fn failed_write_stops_recording() {
+        let mut recorder = CmioRecorder::new(FullDisk);
+        recorder.record(b"response");
+        assert!(recorder.writer.is_none());
+        // Later responses are dropped instead of failing again.
+        recorder.record(b"response");
+    }
+
+    #[test]
+    fn disabled_recorder_records_nothing() {
+        let mut recorder = CmioRecorder::disabled();
+        recorder.record(b"response");
+        assert!(recorder.writer.is_none());
+    }
+}
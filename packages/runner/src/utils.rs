@@ -1,3 +1,4 @@
+use crate::session::CmioRecorder;
 use cartesi_machine::machine::Machine;
 use cartesi_machine::types::cmio::{
     AutomaticReason, CmioRequest, CmioResponseReason, ManualReason,
@@ -5,55 +6,75 @@ use cartesi_machine::types::cmio::{
 use log::info;
 use std::error::Error;
 use vsock_protocol::{
-    Packet, VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RST, VSOCK_TYPE_STREAM,
+    op_name, Hello, Packet, VirtioVsockHdr, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RST,
 };
 
 const GUEST_CID: u32 = 1;
 const HOST_CID: u32 = 3;
 const HOST_PORT: u32 = 1025;
+/// Protocol features the runner advertises in its Hello.
+const RUNNER_FEATURES: u32 = 0;
 
 pub fn send_packet(
     machine: &mut Machine,
+    recorder: &mut CmioRecorder,
     guest_port: u32,
     op: u16,
     payload: &[u8],
 ) -> Result<(), Box<dyn Error>> {
-    info!("Crafting vsock packet with op {}", op);
+    info!("Crafting vsock packet with op {}", op_name(op));
 
-    let hdr = VirtioVsockHdr {
-        src_cid: HOST_CID,
-        dst_cid: GUEST_CID,
-        src_port: HOST_PORT,
-        dst_port: guest_port,
-        len: payload.len() as u32,
-        type_: VSOCK_TYPE_STREAM,
-        op,
-        flags: 0,
-        buf_alloc: 0,
-        fwd_cnt: 0,
-    };
+    let hdr = VirtioVsockHdr::builder()
+        .src(HOST_CID, HOST_PORT)
+        .dst(GUEST_CID, guest_port)
+        .len(payload.len() as u32)
+        .type_stream()
+        .op(op)
+        .build();
 
     let packet = Packet::new(hdr, payload.to_vec());
     let packet_bytes = packet.to_bytes();
 
-    info!("Sending vsock packet hdr {:?} payload {:?}", hdr, payload);
-    machine.send_cmio_response(CmioResponseReason::Advance, &packet_bytes)?;
+    info!("Sending vsock packet {} payload {:?}", hdr, payload);
+    send_cmio_response(machine, recorder, &packet_bytes)?;
     Ok(())
 }
 
-pub fn vsock_connect(machine: &mut Machine, guest_port: u32) -> Result<(), Box<dyn Error>> {
+pub fn vsock_connect(
+    machine: &mut Machine,
+    recorder: &mut CmioRecorder,
+    guest_port: u32,
+) -> Result<(), Box<dyn Error>> {
     info!(
         "Attempting to connect to guest vsock port {}...",
         guest_port
     );
     run_machine_until_yield(machine)?;
-    send_packet(machine, guest_port, VSOCK_OP_REQUEST, &[])?;
+    let local_hello = Hello::new(RUNNER_FEATURES);
+    send_packet(
+        machine,
+        recorder,
+        guest_port,
+        VSOCK_OP_REQUEST,
+        &local_hello.to_bytes(),
+    )?;
     loop {
         run_machine_until_yield(machine)?;
         info!("Machine cycle = {}", machine.mcycle().unwrap());
         match receive_packet(machine)? {
             Some(packet) => {
                 if packet.hdr().op == VSOCK_OP_RESPONSE {
+                    // Guest agents that predate the version exchange reply
+                    // without a Hello and are assumed compatible.
+                    if !packet.payload().is_empty() {
+                        let peer_hello = Hello::from_bytes(packet.payload())
+                            .ok_or("Malformed protocol hello from guest")?;
+                        local_hello.check_compatible(&peer_hello)?;
+                        info!(
+                            "Guest speaks protocol {}, features {:#x}",
+                            peer_hello.version, peer_hello.features
+                        );
+                    }
                     info!("Vsock connection established!");
                     return Ok(());
                 } else if packet.hdr().op == VSOCK_OP_RST {
@@ -68,7 +89,7 @@ pub fn vsock_connect(machine: &mut Machine, guest_port: u32) -> Result<(), Box<d
                 //                return Err("Connection timeout".into());
             }
         }
-        machine.send_cmio_response(CmioResponseReason::Advance, &[])?;
+        send_empty_response(machine, recorder)?;
 
         //sleep(Duration::from_secs(1));
     }
@@ -92,8 +113,21 @@ pub fn run_machine_until_yield(
     }
 }
 
-pub fn send_empty_response(machine: &mut Machine) -> Result<(), Box<dyn Error>> {
-    machine.send_cmio_response(CmioResponseReason::Advance, &[])?;
+pub fn send_empty_response(
+    machine: &mut Machine,
+    recorder: &mut CmioRecorder,
+) -> Result<(), Box<dyn Error>> {
+    send_cmio_response(machine, recorder, &[])
+}
+
+/// Sends a CMIO response to the machine, then hands it to `recorder`.
+fn send_cmio_response(
+    machine: &mut Machine,
+    recorder: &mut CmioRecorder,
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    machine.send_cmio_response(CmioResponseReason::Advance, data)?;
+    recorder.record(data);
     Ok(())
 }
 
@@ -113,7 +147,7 @@ pub fn receive_packet(machine: &mut Machine) -> Result<Option<Packet>, Box<dyn E
 
     if let Some(data) = cmio_data {
         if !data.is_empty() {
-            match Packet::from_bytes(&data) {
+            match Packet::from_bytes_checked(&data) {
                 Ok(packet) => {
                     info!(
                         "Successfully parsed vsock packet from response: {:?}",
@@ -122,7 +156,10 @@ pub fn receive_packet(machine: &mut Machine) -> Result<Option<Packet>, Box<dyn E
                     return Ok(Some(packet));
                 }
                 Err(e) => {
-                    info!("Failed to parse vsock packet from CMIO data: {:?}", e);
+                    info!(
+                        "Dropping CMIO data that is not a valid stream packet: {}",
+                        e
+                    );
                     info!("Raw CMIO data (bytes): {:?}", data);
                 }
             }
@@ -0,0 +1,117 @@
+use crate::ParseError;
+use std::borrow::Cow;
+use std::fmt;
+
+/// Transforms serialized packets for transports that restrict the bytes they
+/// can carry.
+///
+/// The sender applies [`Framing::encode`] to a serialized packet before handing
+/// it to CMIO, and the receiver applies [`Framing::decode`] before parsing it.
+/// Both ends of a channel must use the same framing.
+pub trait Framing: fmt::Debug + Send + Sync {
+    /// Encodes a serialized packet for the wire.
+    fn encode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]>;
+
+    /// Decodes bytes received from the wire back into a serialized packet.
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, ParseError>;
+
+    /// Returns the size of the largest serialized packet whose encoding fits
+    /// in `encoded_cap` bytes, so senders can size packets to the transport's
+    /// buffer.
+    fn max_decoded_len(&self, encoded_cap: usize) -> usize;
+}
+
+/// The identity framing: packets travel as raw bytes, without copying.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RawFraming;
+
+impl Framing for RawFraming {
+    fn encode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Borrowed(bytes)
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, ParseError> {
+        Ok(Cow::Borrowed(bytes))
+    }
+
+    fn max_decoded_len(&self, encoded_cap: usize) -> usize {
+        encoded_cap
+    }
+}
+
+/// Standard (padded) base64 framing, for text-only channels.
+///
+/// Encoding grows a packet by a third, which must still fit the transport's
+/// buffer; see [`Framing::max_decoded_len`].
+#[cfg(feature = "base64")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Base64Framing;
+
+#[cfg(feature = "base64")]
+impl Framing for Base64Framing {
+    fn encode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        use base64::Engine;
+
+        Cow::Owned(
+            base64::engine::general_purpose::STANDARD
+                .encode(bytes)
+                .into_bytes(),
+        )
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, ParseError> {
+        use base64::Engine;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(bytes)
+            .map(Cow::Owned)
+            .map_err(|_| ParseError::InvalidFraming)
+    }
+
+    fn max_decoded_len(&self, encoded_cap: usize) -> usize {
+        // Every started group of three bytes becomes four characters.
+        encoded_cap / 4 * 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_framing_round_trips_without_copying() {
+        let bytes = [1u8, 2, 3, 4];
+        let encoded = RawFraming.encode(&bytes);
+        assert!(matches!(encoded, Cow::Borrowed(_)));
+        assert_eq!(RawFraming.decode(&encoded).unwrap().as_ref(), &bytes);
+        assert_eq!(RawFraming.max_decoded_len(4096), 4096);
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn base64_framing_round_trips() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = Base64Framing.encode(&bytes);
+        assert!(encoded.iter().all(|b| b.is_ascii_graphic()));
+        assert_eq!(Base64Framing.decode(&encoded).unwrap().as_ref(), &bytes[..]);
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn base64_framing_rejects_invalid_input() {
+        assert_eq!(
+            Base64Framing.decode(b"not base64!"),
+            Err(ParseError::InvalidFraming)
+        );
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn base64_max_decoded_len_fits_the_cap() {
+        for cap in [0, 3, 4, 5, 8, 4095, 4096] {
+            let max = Base64Framing.max_decoded_len(cap);
+            assert!(Base64Framing.encode(&vec![0; max]).len() <= cap);
+            assert!(Base64Framing.encode(&vec![0; max + 1]).len() > cap);
+        }
+    }
+}
@@ -1,9 +1,72 @@
 use std::convert::TryInto;
-use std::io::{self, Read};
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::mem;
 
+mod framing;
+mod version;
+
+#[cfg(feature = "base64")]
+pub use framing::Base64Framing;
+pub use framing::{Framing, RawFraming};
+pub use version::{
+    Hello, VersionMismatch, FEATURE_CREDIT, FEATURE_SHUTDOWN_FLAGS, HELLO_SIZE, PROTOCOL_VERSION,
+};
+
+/// Maximum payload length accepted by [`Packet::from_read`].
+pub const MAX_PAYLOAD_SIZE: u32 = 4096;
+
+/// Errors returned when parsing a vsock packet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Fewer than [`HDR_SIZE`] bytes were available for the header.
+    ShortHeader,
+    /// Fewer payload bytes were available than the header's `len` announced.
+    ShortPayload { expected: usize, got: usize },
+    /// The header's `len` exceeds [`MAX_PAYLOAD_SIZE`].
+    PayloadTooLarge { len: u32 },
+    /// The header's `op` is not a known `VSOCK_OP_*` value.
+    UnknownOp { op: u16 },
+    /// The header's `type_` is not `VSOCK_TYPE_STREAM`.
+    UnsupportedType { type_: u16 },
+    /// The bytes could not be decoded by the channel's [`Framing`].
+    InvalidFraming,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ShortHeader => write!(f, "Packet smaller than header"),
+            ParseError::ShortPayload { expected, got } => write!(
+                f,
+                "Packet payload shorter than header length: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::PayloadTooLarge { len } => write!(
+                f,
+                "Payload too large: {} bytes exceeds {}",
+                len, MAX_PAYLOAD_SIZE
+            ),
+            ParseError::UnknownOp { op } => write!(f, "Unknown vsock op {}", op),
+            ParseError::UnsupportedType { type_ } => {
+                write!(f, "Unsupported vsock socket type {}", type_)
+            }
+            ParseError::InvalidFraming => write!(f, "Packet framing could not be decoded"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
 /// A vsock packet, with a header and a payload.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     hdr: VirtioVsockHdr,
     payload: Vec<u8>,
@@ -30,11 +93,20 @@ impl Packet {
         (self.hdr, self.payload)
     }
 
+    /// Returns a view of the packet that borrows its payload.
+    pub fn as_packet_ref(&self) -> PacketRef<'_> {
+        PacketRef::new(self.hdr, &self.payload)
+    }
+
     /// Serializes the full packet (header and payload) into a byte vector.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.hdr.to_bytes();
-        bytes.extend_from_slice(&self.payload);
-        bytes
+        self.as_packet_ref().to_bytes()
+    }
+
+    /// Writes the full packet (header and payload) to the given writer
+    /// without building an intermediate byte vector.
+    pub fn write_to(&self, writer: impl Write) -> io::Result<()> {
+        self.as_packet_ref().write_to(writer)
     }
 
     /// Reads a full vsock packet from the given reader.
@@ -42,14 +114,10 @@ impl Packet {
         let mut hdr_buf = vec![0; HDR_SIZE];
         reader.read_exact(&mut hdr_buf)?;
 
-        let hdr = VirtioVsockHdr::from_bytes(&hdr_buf)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid vsock header"))?;
+        let hdr = VirtioVsockHdr::from_bytes(&hdr_buf).ok_or(ParseError::ShortHeader)?;
 
-        if hdr.len > 4096 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Payload too large",
-            ));
+        if hdr.len > MAX_PAYLOAD_SIZE {
+            return Err(ParseError::PayloadTooLarge { len: hdr.len }.into());
         }
 
         let mut payload = vec![0; hdr.len as usize];
@@ -60,37 +128,154 @@ impl Packet {
         Ok(Self { hdr, payload })
     }
 
+    /// Reads a full vsock packet from the given async reader.
+    ///
+    /// Async counterpart of [`Packet::from_read`], with the same payload size
+    /// limit.
+    #[cfg(feature = "tokio")]
+    pub async fn from_async_read<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        let mut hdr_buf = [0; HDR_SIZE];
+        reader.read_exact(&mut hdr_buf).await?;
+        let hdr = VirtioVsockHdr::from_bytes(&hdr_buf).ok_or(ParseError::ShortHeader)?;
+
+        if hdr.len > MAX_PAYLOAD_SIZE {
+            return Err(ParseError::PayloadTooLarge { len: hdr.len }.into());
+        }
+
+        let mut payload = vec![0; hdr.len as usize];
+        if hdr.len > 0 {
+            reader.read_exact(&mut payload).await?;
+        }
+
+        Ok(Self { hdr, payload })
+    }
+
     /// Creates a packet from a byte slice.
     /// The byte slice is expected to contain the full packet (header + payload).
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
-        if bytes.len() < HDR_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Packet smaller than header",
-            ));
-        }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_len(bytes).map(|(packet, _)| packet)
+    }
+
+    /// Creates a packet from a byte slice like [`Packet::from_bytes`], but also
+    /// rejects headers whose `op` is not a known `VSOCK_OP_*` value or whose
+    /// `type_` is not `VSOCK_TYPE_STREAM`. This separates misaligned or
+    /// corrupted data from valid traffic with an op the caller doesn't handle.
+    pub fn from_bytes_checked(bytes: &[u8]) -> Result<Self, ParseError> {
+        let packet = Self::from_bytes(bytes)?;
+        packet.hdr.validate()?;
+        Ok(packet)
+    }
 
-        let hdr = VirtioVsockHdr::from_bytes(&bytes[..HDR_SIZE])
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid vsock header"))?;
+    /// Creates a packet from the start of a byte slice, returning the packet
+    /// and the number of bytes it occupied (`HDR_SIZE + payload length`).
+    /// Any bytes after the packet are ignored.
+    pub fn from_bytes_with_len(bytes: &[u8]) -> Result<(Self, usize), ParseError> {
+        let hdr = VirtioVsockHdr::from_bytes(bytes).ok_or(ParseError::ShortHeader)?;
 
         let payload_len = hdr.len as usize;
         let expected_total_len = HDR_SIZE + payload_len;
 
         if bytes.len() < expected_total_len {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Packet smaller than indicated by header length",
-            ));
+            return Err(ParseError::ShortPayload {
+                expected: payload_len,
+                got: bytes.len() - HDR_SIZE,
+            });
         }
 
         let payload = bytes[HDR_SIZE..expected_total_len].to_vec();
 
-        Ok(Self { hdr, payload })
+        Ok((Self { hdr, payload }, expected_total_len))
+    }
+
+    /// Parses every packet in a byte slice containing packets back-to-back.
+    /// Returns an error if the slice ends with a partial packet.
+    pub fn parse_all(bytes: &[u8]) -> Result<Vec<Self>, ParseError> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let (packet, len) = Self::from_bytes_with_len(&bytes[offset..])?;
+            offset += len;
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+}
+
+/// A vsock packet whose payload is borrowed rather than owned.
+///
+/// Lets callers frame data that already sits in a buffer (for example a
+/// stream read buffer) without copying it into a [`Packet`] first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketRef<'a> {
+    hdr: VirtioVsockHdr,
+    payload: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Creates a new packet view with the given header and payload.
+    pub fn new(hdr: VirtioVsockHdr, payload: &'a [u8]) -> Self {
+        Self { hdr, payload }
+    }
+
+    /// Returns a reference to the packet's header.
+    pub fn hdr(&self) -> &VirtioVsockHdr {
+        &self.hdr
+    }
+
+    /// Returns the borrowed payload.
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Serializes the full packet (header and payload) into a byte vector.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HDR_SIZE + self.payload.len());
+        bytes.resize(HDR_SIZE, 0);
+        self.hdr.write_to_slice(&mut bytes);
+        bytes.extend_from_slice(self.payload);
+        bytes
+    }
+
+    /// Writes the full packet (header and payload) to the given writer
+    /// without building an intermediate byte vector.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        self.hdr.write_to(&mut writer)?;
+        writer.write_all(self.payload)
+    }
+}
+
+impl From<PacketRef<'_>> for Packet {
+    fn from(packet: PacketRef<'_>) -> Self {
+        Packet::new(packet.hdr, packet.payload.to_vec())
     }
 }
 
 /// The header for a virtio vsock packet.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+///
+/// On the wire every field is little-endian and packed in declaration order,
+/// with no padding, for a total of [`HDR_SIZE`] bytes:
+///
+/// | offset | field       | size |
+/// |--------|-------------|------|
+/// | 0      | `src_cid`   | 4    |
+/// | 4      | `dst_cid`   | 4    |
+/// | 8      | `src_port`  | 4    |
+/// | 12     | `dst_port`  | 4    |
+/// | 16     | `len`       | 4    |
+/// | 20     | `type_`     | 2    |
+/// | 22     | `op`        | 2    |
+/// | 24     | `flags`     | 4    |
+/// | 28     | `buf_alloc` | 4    |
+/// | 32     | `fwd_cnt`   | 4    |
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VirtioVsockHdr {
     pub src_cid: u32,
     pub dst_cid: u32,
@@ -114,21 +299,117 @@ pub const VSOCK_OP_RW: u16 = 5;
 pub const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
 pub const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
 
-pub const HDR_SIZE: usize = mem::size_of::<VirtioVsockHdr>();
+/// `VSOCK_OP_SHUTDOWN` flag: the sender will not receive any more data.
+pub const VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// `VSOCK_OP_SHUTDOWN` flag: the sender will not send any more data.
+pub const VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+/// Size of a serialized [`VirtioVsockHdr`] on the wire.
+///
+/// This is fixed by the wire layout, not by the in-memory layout of the struct,
+/// which the compiler is free to pad or reorder.
+pub const HDR_SIZE: usize = 36;
+
+// The wire size must match the sum of the serialized field widths.
+const _: () = assert!(
+    HDR_SIZE == 8 * mem::size_of::<u32>() + 2 * mem::size_of::<u16>(),
+    "HDR_SIZE does not match the VirtioVsockHdr wire layout"
+);
+
+/// Returns the symbolic name of a `VSOCK_OP_*` value, or `"UNKNOWN"`.
+pub fn op_name(op: u16) -> &'static str {
+    match op {
+        VSOCK_OP_REQUEST => "REQUEST",
+        VSOCK_OP_RESPONSE => "RESPONSE",
+        VSOCK_OP_RST => "RST",
+        VSOCK_OP_SHUTDOWN => "SHUTDOWN",
+        VSOCK_OP_RW => "RW",
+        VSOCK_OP_CREDIT_UPDATE => "CREDIT_UPDATE",
+        VSOCK_OP_CREDIT_REQUEST => "CREDIT_REQUEST",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Formats bytes as a hex dump, 16 bytes per line, each line prefixed with
+/// its offset. Intended for logging raw CMIO data that failed to parse.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    use std::fmt::Write as _;
+
+    let mut out = String::new();
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = write!(out, "{:04x}:", i * 16);
+        for byte in chunk {
+            let _ = write!(out, " {:02x}", byte);
+        }
+    }
+    out
+}
 
 impl VirtioVsockHdr {
+    /// Returns a builder for a header with every field set to zero.
+    pub fn builder() -> VirtioVsockHdrBuilder {
+        VirtioVsockHdrBuilder::default()
+    }
+
+    /// Checks that `op` is a known `VSOCK_OP_*` value and `type_` is
+    /// `VSOCK_TYPE_STREAM`.
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if !(VSOCK_OP_REQUEST..=VSOCK_OP_CREDIT_REQUEST).contains(&self.op) {
+            return Err(ParseError::UnknownOp { op: self.op });
+        }
+        if self.type_ != VSOCK_TYPE_STREAM {
+            return Err(ParseError::UnsupportedType { type_: self.type_ });
+        }
+        Ok(())
+    }
+
+    /// Decodes the `flags` of a `VSOCK_OP_SHUTDOWN` header as
+    /// `(no_more_rcv, no_more_send)`, from the sender's point of view.
+    pub fn shutdown_mode(&self) -> (bool, bool) {
+        (
+            self.flags & VSOCK_SHUTDOWN_RCV != 0,
+            self.flags & VSOCK_SHUTDOWN_SEND != 0,
+        )
+    }
+
+    /// Returns a hex dump of the header's wire bytes.
+    pub fn debug_dump(&self) -> String {
+        hex_dump(&self.to_bytes())
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(HDR_SIZE);
-        bytes.extend_from_slice(&self.src_cid.to_le_bytes());
-        bytes.extend_from_slice(&self.dst_cid.to_le_bytes());
-        bytes.extend_from_slice(&self.src_port.to_le_bytes());
-        bytes.extend_from_slice(&self.dst_port.to_le_bytes());
-        bytes.extend_from_slice(&self.len.to_le_bytes());
-        bytes.extend_from_slice(&self.type_.to_le_bytes());
-        bytes.extend_from_slice(&self.op.to_le_bytes());
-        bytes.extend_from_slice(&self.flags.to_le_bytes());
-        bytes.extend_from_slice(&self.buf_alloc.to_le_bytes());
-        bytes.extend_from_slice(&self.fwd_cnt.to_le_bytes());
+        self.encode().to_vec()
+    }
+
+    /// Writes the header's wire bytes to the given writer without allocating.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.encode())
+    }
+
+    /// Writes the header's wire bytes into the start of `buf` without
+    /// allocating, returning the number of bytes written ([`HDR_SIZE`]), or
+    /// `None` if `buf` is shorter than a header.
+    pub fn write_to_slice(&self, buf: &mut [u8]) -> Option<usize> {
+        let bytes = buf.get_mut(..HDR_SIZE)?;
+        bytes[0..4].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.len.to_le_bytes());
+        bytes[20..22].copy_from_slice(&self.type_.to_le_bytes());
+        bytes[22..24].copy_from_slice(&self.op.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        Some(HDR_SIZE)
+    }
+
+    fn encode(&self) -> [u8; HDR_SIZE] {
+        let mut bytes = [0; HDR_SIZE];
+        self.write_to_slice(&mut bytes);
         bytes
     }
 
@@ -162,3 +443,534 @@ impl VirtioVsockHdr {
         })
     }
 }
+
+impl fmt::Display for VirtioVsockHdr {
+    /// Formats the header as `src_cid:src_port -> dst_cid:dst_port op=RW len=N`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} -> {}:{} op={} len={}",
+            self.src_cid,
+            self.src_port,
+            self.dst_cid,
+            self.dst_port,
+            op_name(self.op),
+            self.len
+        )
+    }
+}
+
+/// Builder for [`VirtioVsockHdr`]. Fields that are not set default to zero.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct VirtioVsockHdrBuilder {
+    hdr: VirtioVsockHdr,
+}
+
+impl VirtioVsockHdrBuilder {
+    /// Sets the source CID and port.
+    pub fn src(mut self, cid: u32, port: u32) -> Self {
+        self.hdr.src_cid = cid;
+        self.hdr.src_port = port;
+        self
+    }
+
+    /// Sets the destination CID and port.
+    pub fn dst(mut self, cid: u32, port: u32) -> Self {
+        self.hdr.dst_cid = cid;
+        self.hdr.dst_port = port;
+        self
+    }
+
+    /// Sets the payload length.
+    pub fn len(mut self, len: u32) -> Self {
+        self.hdr.len = len;
+        self
+    }
+
+    /// Sets the socket type.
+    pub fn type_(mut self, type_: u16) -> Self {
+        self.hdr.type_ = type_;
+        self
+    }
+
+    /// Sets the socket type to `VSOCK_TYPE_STREAM`.
+    pub fn type_stream(self) -> Self {
+        self.type_(VSOCK_TYPE_STREAM)
+    }
+
+    /// Sets the operation.
+    pub fn op(mut self, op: u16) -> Self {
+        self.hdr.op = op;
+        self
+    }
+
+    /// Sets the flags.
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.hdr.flags = flags;
+        self
+    }
+
+    /// Sets the advertised receive buffer size.
+    pub fn buf_alloc(mut self, buf_alloc: u32) -> Self {
+        self.hdr.buf_alloc = buf_alloc;
+        self
+    }
+
+    /// Sets the forwarded byte count.
+    pub fn fwd_cnt(mut self, fwd_cnt: u32) -> Self {
+        self.hdr.fwd_cnt = fwd_cnt;
+        self
+    }
+
+    /// Returns the built header.
+    pub fn build(self) -> VirtioVsockHdr {
+        self.hdr
+    }
+}
+
+/// Credit-based flow control state for one side of a stream connection.
+///
+/// The local side advertises `buf_alloc` (its receive buffer size) and
+/// `fwd_cnt` (bytes consumed from that buffer so far) in the headers it sends.
+/// The peer's values, learned from the headers it sends back, bound how many
+/// bytes may be in flight towards it.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CreditState {
+    buf_alloc: u32,
+    fwd_cnt: u32,
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl CreditState {
+    /// Creates a new credit state advertising a local receive buffer of
+    /// `buf_alloc` bytes.
+    pub fn new(buf_alloc: u32) -> Self {
+        Self {
+            buf_alloc,
+            ..Self::default()
+        }
+    }
+
+    /// Records the peer's advertised `buf_alloc` and `fwd_cnt` from a header it sent.
+    pub fn update_from_hdr(&mut self, hdr: &VirtioVsockHdr) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+
+    /// Records `len` payload bytes sent to the peer.
+    pub fn record_sent(&mut self, len: u32) {
+        self.tx_cnt = self.tx_cnt.wrapping_add(len);
+    }
+
+    /// Records `len` received bytes consumed by the local reader.
+    pub fn record_forwarded(&mut self, len: u32) {
+        self.fwd_cnt = self.fwd_cnt.wrapping_add(len);
+    }
+
+    /// Returns how many more payload bytes the peer can currently accept.
+    pub fn peer_free(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    /// Returns the receive buffer size most recently advertised by the peer.
+    /// Zero means the peer has not advertised any credit.
+    pub fn peer_buf_alloc(&self) -> u32 {
+        self.peer_buf_alloc
+    }
+
+    /// Returns the local receive buffer size advertised to the peer.
+    pub fn buf_alloc(&self) -> u32 {
+        self.buf_alloc
+    }
+
+    /// Returns the number of received bytes consumed by the local reader.
+    pub fn fwd_cnt(&self) -> u32 {
+        self.fwd_cnt
+    }
+
+    /// Builds a `VSOCK_OP_CREDIT_UPDATE` header advertising the local credit.
+    /// Addressing and type are copied from `base`, which should be a header
+    /// addressed to the peer.
+    pub fn credit_update_hdr(&self, base: &VirtioVsockHdr) -> VirtioVsockHdr {
+        VirtioVsockHdr {
+            len: 0,
+            op: VSOCK_OP_CREDIT_UPDATE,
+            flags: 0,
+            buf_alloc: self.buf_alloc,
+            fwd_cnt: self.fwd_cnt,
+            ..*base
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rw_hdr(len: u32) -> VirtioVsockHdr {
+        VirtioVsockHdr::builder()
+            .src(2, 1000)
+            .dst(3, 8080)
+            .len(len)
+            .type_stream()
+            .op(VSOCK_OP_RW)
+            .build()
+    }
+
+    #[test]
+    fn display_formats_addresses_op_and_len() {
+        assert_eq!(rw_hdr(42).to_string(), "2:1000 -> 3:8080 op=RW len=42");
+    }
+
+    #[test]
+    fn op_name_covers_every_op() {
+        let names: Vec<_> = (VSOCK_OP_REQUEST..=VSOCK_OP_CREDIT_REQUEST)
+            .map(op_name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "REQUEST",
+                "RESPONSE",
+                "RST",
+                "SHUTDOWN",
+                "RW",
+                "CREDIT_UPDATE",
+                "CREDIT_REQUEST"
+            ]
+        );
+        assert_eq!(op_name(0), "UNKNOWN");
+        assert_eq!(op_name(99), "UNKNOWN");
+    }
+
+    fn rw_packet(payload: &[u8]) -> Packet {
+        Packet::new(rw_hdr(payload.len() as u32), payload.to_vec())
+    }
+
+    #[test]
+    fn parse_all_splits_concatenated_packets() {
+        let packets = [rw_packet(b"first"), rw_packet(b""), rw_packet(b"third")];
+        let bytes: Vec<u8> = packets.iter().flat_map(Packet::to_bytes).collect();
+
+        let parsed = Packet::parse_all(&bytes).unwrap();
+        let payloads: Vec<&[u8]> = parsed.iter().map(Packet::payload).collect();
+        assert_eq!(payloads, [&b"first"[..], b"", b"third"]);
+        assert_eq!(parsed, packets);
+    }
+
+    #[test]
+    fn parse_all_rejects_a_trailing_partial_packet() {
+        let mut bytes = rw_packet(b"whole").to_bytes();
+        let partial = rw_packet(b"partial").to_bytes();
+
+        bytes.extend_from_slice(&partial[..HDR_SIZE + 3]);
+        assert_eq!(
+            Packet::parse_all(&bytes),
+            Err(ParseError::ShortPayload {
+                expected: 7,
+                got: 3
+            })
+        );
+
+        bytes.truncate(bytes.len() - 3 - HDR_SIZE / 2);
+        assert_eq!(Packet::parse_all(&bytes), Err(ParseError::ShortHeader));
+    }
+
+    fn peer_credit(buf_alloc: u32, fwd_cnt: u32) -> VirtioVsockHdr {
+        VirtioVsockHdr::builder()
+            .buf_alloc(buf_alloc)
+            .fwd_cnt(fwd_cnt)
+            .build()
+    }
+
+    #[test]
+    fn credit_window_closes_until_the_peer_forwards() {
+        let mut credit = CreditState::new(4096);
+        credit.update_from_hdr(&peer_credit(100, 0));
+        assert_eq!(credit.peer_free(), 100);
+
+        credit.record_sent(60);
+        assert_eq!(credit.peer_free(), 40);
+        credit.record_sent(40);
+        assert_eq!(credit.peer_free(), 0);
+
+        // A header that does not advance fwd_cnt leaves the window closed.
+        credit.update_from_hdr(&peer_credit(100, 0));
+        assert_eq!(credit.peer_free(), 0);
+
+        credit.update_from_hdr(&peer_credit(100, 30));
+        assert_eq!(credit.peer_free(), 30);
+    }
+
+    #[test]
+    fn credit_counters_wrap() {
+        let start = u32::MAX - 10;
+        let mut credit = CreditState::new(4096);
+        credit.update_from_hdr(&peer_credit(100, start));
+        credit.record_sent(start);
+        assert_eq!(credit.peer_free(), 100);
+
+        // tx_cnt wraps past zero.
+        credit.record_sent(100);
+        assert_eq!(credit.peer_free(), 0);
+
+        // So does the peer's fwd_cnt.
+        credit.update_from_hdr(&peer_credit(100, start.wrapping_add(60)));
+        assert_eq!(credit.peer_free(), 60);
+    }
+
+    #[test]
+    fn credit_advertises_local_buffer_and_forwarded_bytes() {
+        let mut credit = CreditState::new(4096);
+        credit.record_forwarded(u32::MAX);
+        credit.record_forwarded(2);
+        assert_eq!(credit.buf_alloc(), 4096);
+        assert_eq!(credit.fwd_cnt(), 1);
+
+        let update = credit.credit_update_hdr(&rw_hdr(5));
+        assert_eq!(update.op, VSOCK_OP_CREDIT_UPDATE);
+        assert_eq!((update.len, update.buf_alloc, update.fwd_cnt), (0, 4096, 1));
+        assert_eq!((update.src_port, update.dst_port), (1000, 8080));
+    }
+
+    #[test]
+    fn write_to_slice_matches_to_bytes() {
+        let hdr = VirtioVsockHdr {
+            flags: VSOCK_SHUTDOWN_SEND,
+            buf_alloc: 4096,
+            fwd_cnt: 0x0102_0304,
+            ..rw_hdr(42)
+        };
+        let mut buf = [0xff; HDR_SIZE + 4];
+        assert_eq!(hdr.write_to_slice(&mut buf), Some(HDR_SIZE));
+        assert_eq!(&buf[..HDR_SIZE], hdr.to_bytes().as_slice());
+        // Bytes past the header are left alone.
+        assert_eq!(buf[HDR_SIZE..], [0xff; 4]);
+        assert_eq!(VirtioVsockHdr::from_bytes(&buf), Some(hdr));
+    }
+
+    #[test]
+    fn write_to_slice_rejects_a_short_buffer() {
+        let mut buf = [0; HDR_SIZE - 1];
+        assert_eq!(rw_hdr(0).write_to_slice(&mut buf), None);
+        assert_eq!(buf, [0; HDR_SIZE - 1]);
+    }
+
+    #[test]
+    fn shutdown_mode_decodes_each_flag_combination() {
+        let mode = |flags| VirtioVsockHdr { flags, ..rw_hdr(0) }.shutdown_mode();
+        assert_eq!(mode(0), (false, false));
+        assert_eq!(mode(VSOCK_SHUTDOWN_RCV), (true, false));
+        assert_eq!(mode(VSOCK_SHUTDOWN_SEND), (false, true));
+        assert_eq!(mode(VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND), (true, true));
+        // Unknown bits are ignored.
+        assert_eq!(mode(0x4 | VSOCK_SHUTDOWN_SEND), (false, true));
+    }
+
+    #[test]
+    fn to_bytes_is_hdr_size() {
+        assert_eq!(VirtioVsockHdr::default().to_bytes().len(), HDR_SIZE);
+        assert_eq!(rw_hdr(42).to_bytes().len(), HDR_SIZE);
+
+        let mut written = Vec::new();
+        rw_hdr(42).write_to(&mut written).unwrap();
+        assert_eq!(written.len(), HDR_SIZE);
+    }
+
+    #[test]
+    fn builder_matches_struct_literal() {
+        let built = VirtioVsockHdr::builder()
+            .src(2, 1000)
+            .dst(3, 8080)
+            .len(42)
+            .type_stream()
+            .op(VSOCK_OP_SHUTDOWN)
+            .flags(VSOCK_SHUTDOWN_SEND)
+            .buf_alloc(4096)
+            .fwd_cnt(7)
+            .build();
+        let literal = VirtioVsockHdr {
+            src_cid: 2,
+            dst_cid: 3,
+            src_port: 1000,
+            dst_port: 8080,
+            len: 42,
+            type_: VSOCK_TYPE_STREAM,
+            op: VSOCK_OP_SHUTDOWN,
+            flags: VSOCK_SHUTDOWN_SEND,
+            buf_alloc: 4096,
+            fwd_cnt: 7,
+        };
+        assert_eq!(built, literal);
+        assert_eq!(VirtioVsockHdr::builder().build(), VirtioVsockHdr::default());
+    }
+
+    #[test]
+    fn from_bytes_with_len_reports_the_packet_length() {
+        let packet = rw_packet(b"payload");
+        let mut bytes = packet.to_bytes();
+        assert_eq!(
+            Packet::from_bytes_with_len(&bytes),
+            Ok((packet.clone(), HDR_SIZE + 7))
+        );
+
+        // Trailing bytes are not counted.
+        bytes.extend_from_slice(b"trailing");
+        assert_eq!(
+            Packet::from_bytes_with_len(&bytes),
+            Ok((packet, HDR_SIZE + 7))
+        );
+
+        let empty = rw_packet(b"");
+        assert_eq!(
+            Packet::from_bytes_with_len(&empty.to_bytes()),
+            Ok((empty, HDR_SIZE))
+        );
+    }
+
+    #[test]
+    fn write_to_round_trips_through_from_read() {
+        let packet = rw_packet(b"round trip");
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, packet.to_bytes());
+        assert_eq!(Packet::from_read(bytes.as_slice()).unwrap(), packet);
+
+        // A borrowed packet writes the same bytes.
+        let mut borrowed = Vec::new();
+        packet.as_packet_ref().write_to(&mut borrowed).unwrap();
+        assert_eq!(borrowed, bytes);
+    }
+
+    #[test]
+    fn from_read_rejects_an_oversize_payload() {
+        let hdr = rw_hdr(MAX_PAYLOAD_SIZE + 1);
+        let err = Packet::from_read(hdr.to_bytes().as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips_packets_and_credit() {
+        let packet = rw_packet(b"serde");
+        let json = serde_json::to_string(&packet).unwrap();
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap(), packet);
+
+        let mut credit = CreditState::new(4096);
+        credit.update_from_hdr(&peer_credit(100, 7));
+        credit.record_sent(20);
+        credit.record_forwarded(3);
+        let json = serde_json::to_string(&credit).unwrap();
+        let restored: CreditState = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            (
+                restored.buf_alloc(),
+                restored.fwd_cnt(),
+                restored.peer_free()
+            ),
+            (4096, 3, 87)
+        );
+    }
+
+    #[test]
+    fn from_bytes_checked_rejects_unknown_ops() {
+        let valid = rw_packet(b"ok");
+        assert_eq!(Packet::from_bytes_checked(&valid.to_bytes()), Ok(valid));
+
+        let unknown = Packet::new(
+            VirtioVsockHdr {
+                op: 9999,
+                ..rw_hdr(0)
+            },
+            Vec::new(),
+        );
+        assert_eq!(
+            Packet::from_bytes_checked(&unknown.to_bytes()),
+            Err(ParseError::UnknownOp { op: 9999 })
+        );
+        // The lenient parser still accepts it.
+        assert_eq!(Packet::from_bytes(&unknown.to_bytes()), Ok(unknown));
+    }
+
+    #[test]
+    fn from_bytes_checked_rejects_non_stream_types() {
+        let stream = Packet::new(rw_hdr(0), Vec::new());
+        assert_eq!(stream.hdr().type_, VSOCK_TYPE_STREAM);
+        assert_eq!(Packet::from_bytes_checked(&stream.to_bytes()), Ok(stream));
+
+        // SOCK_SEQPACKET is 2.
+        let seqpacket = Packet::new(
+            VirtioVsockHdr {
+                type_: 2,
+                ..rw_hdr(0)
+            },
+            Vec::new(),
+        );
+        assert_eq!(
+            Packet::from_bytes_checked(&seqpacket.to_bytes()),
+            Err(ParseError::UnsupportedType { type_: 2 })
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn from_async_read_reads_packets_from_a_duplex() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let packet = rw_packet(b"async");
+        let bytes = packet.to_bytes();
+        let send = tokio::spawn(async move { writer.write_all(&bytes).await });
+
+        assert_eq!(Packet::from_async_read(&mut reader).await.unwrap(), packet);
+        send.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn from_async_read_rejects_an_oversize_payload() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        writer
+            .write_all(&rw_hdr(MAX_PAYLOAD_SIZE + 1).to_bytes())
+            .await
+            .unwrap();
+
+        let err = Packet::from_async_read(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.into_inner().unwrap().downcast_ref::<ParseError>(),
+            Some(&ParseError::PayloadTooLarge {
+                len: MAX_PAYLOAD_SIZE + 1
+            })
+        );
+    }
+
+    #[test]
+    fn fully_populated_header_round_trips() {
+        // Distinct bytes in every field catch swapped or misplaced offsets.
+        let hdr = VirtioVsockHdr {
+            src_cid: 0x0102_0304,
+            dst_cid: 0x0506_0708,
+            src_port: 0x090a_0b0c,
+            dst_port: 0x0d0e_0f10,
+            len: 0x1112_1314,
+            type_: 0x1516,
+            op: 0x1718,
+            flags: 0x191a_1b1c,
+            buf_alloc: 0x1d1e_1f20,
+            fwd_cnt: 0x2122_2324,
+        };
+        let bytes = hdr.to_bytes();
+        assert_eq!(bytes.len(), 36);
+        assert_eq!(HDR_SIZE, 36);
+        assert_eq!(&bytes[..4], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(&bytes[32..], [0x24, 0x23, 0x22, 0x21]);
+        assert_eq!(VirtioVsockHdr::from_bytes(&bytes), Some(hdr));
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+/// Version of the vsock-over-CMIO protocol implemented by this crate.
+///
+/// Peers advertising a different version refuse to connect rather than risk
+/// misreading each other's traffic.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Feature bit: credit-based flow control (`VSOCK_OP_CREDIT_*`).
+pub const FEATURE_CREDIT: u32 = 1 << 0;
+/// Feature bit: half-close through `VSOCK_OP_SHUTDOWN` flags.
+pub const FEATURE_SHUTDOWN_FLAGS: u32 = 1 << 1;
+
+/// Size of a serialized [`Hello`].
+pub const HELLO_SIZE: usize = 6;
+
+/// The protocol version and features a peer advertises in the payload of its
+/// `VSOCK_OP_REQUEST` or `VSOCK_OP_RESPONSE`.
+///
+/// Serialized little-endian as `version: u16` followed by `features: u32`.
+/// Peers that predate the exchange send an empty payload.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub features: u32,
+}
+
+impl Hello {
+    /// Creates a hello for [`PROTOCOL_VERSION`] with the given feature bits.
+    pub fn new(features: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// Serializes the hello into the [`HELLO_SIZE`]-byte wire format.
+    pub fn to_bytes(&self) -> [u8; HELLO_SIZE] {
+        let mut bytes = [0; HELLO_SIZE];
+        bytes[0..2].copy_from_slice(&self.version.to_le_bytes());
+        bytes[2..6].copy_from_slice(&self.features.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a hello from the start of a payload. Trailing bytes are ignored
+    /// so later versions can extend it.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HELLO_SIZE {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
+            features: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+        })
+    }
+
+    /// Checks that a peer's hello can interoperate with this one.
+    pub fn check_compatible(&self, peer: &Hello) -> Result<(), VersionMismatch> {
+        if self.version != peer.version {
+            return Err(VersionMismatch {
+                local: self.version,
+                peer: peer.version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the feature bits supported by both this side and `peer`.
+    pub fn common_features(&self, peer: &Hello) -> u32 {
+        self.features & peer.features
+    }
+}
+
+/// The peer advertised a protocol version this side does not speak.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub local: u16,
+    pub peer: u16,
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Protocol version mismatch: local version {}, peer version {}",
+            self.local, self.peer
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let hello = Hello::new(FEATURE_CREDIT | FEATURE_SHUTDOWN_FLAGS);
+        assert_eq!(Hello::from_bytes(&hello.to_bytes()), Some(hello));
+
+        let mut extended = hello.to_bytes().to_vec();
+        extended.extend_from_slice(&[0xff; 4]);
+        assert_eq!(Hello::from_bytes(&extended), Some(hello));
+    }
+
+    #[test]
+    fn from_bytes_rejects_short_input() {
+        let bytes = Hello::new(0).to_bytes();
+        for len in 0..HELLO_SIZE {
+            assert_eq!(Hello::from_bytes(&bytes[..len]), None);
+        }
+    }
+
+    #[test]
+    fn check_compatible_rejects_other_versions() {
+        let local = Hello::new(FEATURE_CREDIT);
+        let peer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            features: FEATURE_CREDIT,
+        };
+        assert_eq!(
+            local.check_compatible(&peer),
+            Err(VersionMismatch {
+                local: PROTOCOL_VERSION,
+                peer: PROTOCOL_VERSION + 1,
+            })
+        );
+        assert_eq!(local.check_compatible(&Hello::new(0)), Ok(()));
+    }
+
+    #[test]
+    fn common_features_is_the_intersection() {
+        let local = Hello::new(FEATURE_CREDIT | FEATURE_SHUTDOWN_FLAGS);
+        let peer = Hello::new(FEATURE_CREDIT);
+        assert_eq!(local.common_features(&peer), FEATURE_CREDIT);
+    }
+}